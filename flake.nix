@@ -28,9 +28,6 @@
         envVars = with pkgs; {
           BWRAP = "${bubblewrap}/bin/bwrap";
           PERL = "${perl}/bin/perl";
-          PRLIMIT = "${util-linux}/bin/prlimit";
-          TIMEOUT = "${coreutils}/bin/timeout";
-          ALLOW_DIRS = "${perl},${util-linux},${util-linux.lib},${glibc},${libxcrypt}";
         };
       in
       rec {
@@ -67,6 +64,8 @@
                     Environment = concatStringsSep " " (pkgs.lib.mapAttrsToList (name: value: name + "=" + value) envVars);
                     User = "perlsub";
                     Group = "nogroup";
+                    # lets perlsub create a cgroup per run
+                    Delegate = "yes";
                 };
               };
             };
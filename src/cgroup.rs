@@ -0,0 +1,259 @@
+//! Per-worker cgroup v2 limits and accounting inside a delegated subtree.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read as _, Seek as _, SeekFrom, Write as _},
+    os::fd::{AsRawFd as _, OwnedFd},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use tokio::process::Command;
+
+use crate::{config::Config, limits::Limits};
+
+const CGROUP_MOUNT: &str = "/sys/fs/cgroup";
+
+/// Controllers the per-run cgroups need.
+const CONTROLLERS: &[&str] = &["memory", "pids", "cpu"];
+
+/// Period `cpu.max` quotas are expressed in, microseconds.
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// Root of the delegated subtree. The bot itself is moved into a `bot` leaf
+/// so that controllers can be enabled for the per-worker siblings.
+#[derive(Debug)]
+pub struct Cgroups {
+    root: PathBuf,
+    next_id: AtomicU64,
+}
+
+/// Resources a worker used so far.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Usage {
+    /// `usage_usec` from `cpu.stat`.
+    pub cpu_time: Option<Duration>,
+    /// How often the OOM killer fired inside the cgroup.
+    pub oom_kills: u64,
+}
+
+/// The bot's own cgroup, from the unified hierarchy line of
+/// `/proc/self/cgroup`.
+fn own_cgroup() -> io::Result<PathBuf> {
+    let contents = fs::read_to_string("/proc/self/cgroup")?;
+    let path = contents
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not on cgroup v2"))?;
+    Ok(Path::new(CGROUP_MOUNT).join(path.trim_start_matches('/')))
+}
+
+impl Cgroups {
+    /// Sets up the subtree, `None` if cgroups are disabled or can't be used,
+    /// in which case only rlimits apply.
+    pub fn from_config(cfg: &Config) -> Option<Self> {
+        if !cfg.cgroups {
+            return None;
+        }
+
+        let root = match &cfg.cgroup_root {
+            Some(root) => Ok(root.clone()),
+            None => own_cgroup(),
+        };
+        match root.and_then(Self::setup) {
+            Ok(cgroups) => {
+                tracing::info!(root = ?cgroups.root, "using cgroup v2 for perl runs");
+                Some(cgroups)
+            }
+            Err(err) => {
+                tracing::warn!(%err, "cgroups are unavailable, falling back to rlimits only");
+                None
+            }
+        }
+    }
+
+    fn setup(root: PathBuf) -> io::Result<Self> {
+        // Only cgroup2 directories have this file, which also tells us
+        // whether the parent delegated the controllers we need.
+        let available = fs::read_to_string(root.join("cgroup.controllers"))?;
+        for controller in CONTROLLERS {
+            if !available.split_whitespace().any(|name| name == *controller) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("{controller} controller is not available"),
+                ));
+            }
+        }
+
+        let bot = root.join("bot");
+        match fs::create_dir(&bot) {
+            Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+            _ => {}
+        }
+        fs::write(bot.join("cgroup.procs"), std::process::id().to_string())?;
+        let enable = CONTROLLERS
+            .iter()
+            .map(|controller| format!("+{controller}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        fs::write(root.join("cgroup.subtree_control"), enable)?;
+        Self::remove_stale(&root);
+
+        Ok(Self {
+            root,
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// Kills and removes worker cgroups a previous instance left behind.
+    fn remove_stale(root: &Path) {
+        let Ok(entries) = fs::read_dir(root) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            if !entry.file_name().to_string_lossy().starts_with("worker-") {
+                continue;
+            }
+
+            let path = entry.path();
+            let _ = fs::write(path.join("cgroup.kill"), "1");
+            if let Err(err) = fs::remove_dir(&path) {
+                tracing::warn!(%err, ?path, "failed to remove stale cgroup");
+            }
+        }
+    }
+
+    /// Creates a cgroup for a worker with `limits` applied.
+    pub fn create(&self, limits: &Limits) -> io::Result<WorkerCgroup> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let path = self.root.join(format!("worker-{id}"));
+        fs::create_dir(&path)?;
+
+        let configure = || {
+            let procs = OpenOptions::new()
+                .write(true)
+                .open(path.join("cgroup.procs"))?;
+            let cgroup = WorkerCgroup {
+                path: path.clone(),
+                procs: procs.into(),
+            };
+            fs::write(path.join("memory.swap.max"), "0")?;
+            cgroup.set_limits(limits)?;
+            Ok(cgroup)
+        };
+        configure().inspect_err(|_| {
+            // Nothing could have entered it yet, so this can't be busy.
+            let _ = fs::remove_dir(&path);
+        })
+    }
+}
+
+/// `memory.peak` after a reset. The reset only applies to reads through the
+/// same descriptor.
+#[derive(Debug)]
+pub struct PeakMemory(File);
+
+impl PeakMemory {
+    /// Bytes since the reset.
+    pub fn read(&mut self) -> Option<u64> {
+        let mut contents = String::new();
+        self.0.seek(SeekFrom::Start(0)).ok()?;
+        self.0.read_to_string(&mut contents).ok()?;
+        contents.trim().parse().ok()
+    }
+}
+
+/// Cgroup of a single worker and the runs it forks, removed with
+/// [`WorkerCgroup::remove`].
+#[derive(Debug)]
+pub struct WorkerCgroup {
+    path: PathBuf,
+    procs: OwnedFd,
+}
+
+impl WorkerCgroup {
+    /// Writes `limits`, which can differ between runs of the same worker.
+    pub fn set_limits(&self, limits: &Limits) -> io::Result<()> {
+        fs::write(self.path.join("memory.max"), limits.memory_max.to_string())?;
+        fs::write(self.path.join("pids.max"), limits.pids_max.to_string())?;
+        let quota = CPU_PERIOD_US * limits.cpu_max_percent / 100;
+        fs::write(
+            self.path.join("cpu.max"),
+            format!("{quota} {CPU_PERIOD_US}"),
+        )
+    }
+
+    /// Makes the spawned process join the cgroup before exec.
+    pub fn enter(&self, cmd: &mut Command) -> io::Result<()> {
+        let procs = self.procs.try_clone()?;
+        let hook = move || {
+            // "0" moves the writing process.
+            // SAFETY: writing a valid buffer to a descriptor we own.
+            if unsafe { libc::write(procs.as_raw_fd(), b"0".as_ptr().cast(), 1) } != 1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        };
+        // SAFETY: the hook only calls write(2).
+        unsafe {
+            cmd.pre_exec(hook);
+        }
+        Ok(())
+    }
+
+    fn read(&self, file: &str) -> Option<String> {
+        fs::read_to_string(self.path.join(file)).ok()
+    }
+
+    fn stat(&self, file: &str, key: &str) -> Option<u64> {
+        self.read(file)?.lines().find_map(|line| {
+            let (name, value) = line.split_once(' ')?;
+            (name == key).then(|| value.parse().ok())?
+        })
+    }
+
+    /// Starts measuring the peak memory of the next run. `memory.peak` only
+    /// covers the worker's lifetime unless it is reset, which kernels before
+    /// 6.12 can't do, so those get `None`.
+    pub fn reset_peak(&self) -> Option<PeakMemory> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.path.join("memory.peak"))
+            .ok()?;
+        file.write_all(b"reset").ok()?;
+        Some(PeakMemory(file))
+    }
+
+    pub fn usage(&self) -> Usage {
+        Usage {
+            cpu_time: self
+                .stat("cpu.stat", "usage_usec")
+                .map(Duration::from_micros),
+            oom_kills: self.stat("memory.events", "oom_kill").unwrap_or(0),
+        }
+    }
+
+    /// Kills whatever is left in the cgroup and removes it.
+    pub async fn remove(self) {
+        if let Err(err) = fs::write(self.path.join("cgroup.kill"), "1") {
+            tracing::debug!(%err, path = ?self.path, "failed to kill cgroup");
+        }
+
+        // Killed processes take a moment to leave the cgroup.
+        for _ in 0..10 {
+            match fs::remove_dir(&self.path) {
+                Ok(()) => return,
+                Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                Err(err) => {
+                    tracing::warn!(%err, path = ?self.path, "failed to remove cgroup");
+                    return;
+                }
+            }
+        }
+        tracing::warn!(path = ?self.path, "cgroup is still busy, leaving it behind");
+    }
+}
@@ -0,0 +1,207 @@
+use std::{fmt, path::PathBuf};
+
+use color_eyre::eyre::{self, ensure};
+use serde::Deserialize;
+
+use crate::{
+    limits::{LimitOverrides, Limits},
+    perl::Mode,
+    policy::Policy,
+    sandbox::{self, SandboxKind},
+};
+
+/// Opcodes the Safe compartment permits by default: Safe's own defaults,
+/// which cover string, regex and data structure ops, plus I/O on the handles
+/// a run already has, math, sorting and packing.
+const DEFAULT_OPCODES: &[&str] = &[
+    ":default",
+    ":base_io",
+    ":base_math",
+    "sort",
+    "pack",
+    "unpack",
+    "time",
+    "exit",
+];
+
+#[derive(Deserialize)]
+#[serde(transparent)]
+pub struct Token(pub String);
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Token(hidden)")
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    pub token: Token,
+    pub db_path: PathBuf,
+    /// Perl runs at the same time, and so the most workers the pool keeps.
+    #[serde(default = "default_max_parallel")]
+    pub max_parallel: usize,
+    /// Workers started up front.
+    #[serde(default = "default_pool_prestart")]
+    pub pool_prestart: usize,
+    /// Runs a worker forks before it is replaced by a fresh one.
+    #[serde(default = "default_worker_max_jobs")]
+    pub worker_max_jobs: u64,
+    /// Perl output past this many bytes is dropped.
+    #[serde(default = "default_max_output_bytes")]
+    pub max_output_bytes: usize,
+    /// Results longer than this many characters are replied with as a diff
+    /// if that's shorter. Off by default, `;diff` asks for one either way.
+    #[serde(default)]
+    pub auto_diff_chars: usize,
+    /// Allow `/e` and `/ee`. Chats can only forbid more, not less.
+    #[serde(default = "default_allow")]
+    pub allow_eval: bool,
+    /// Allow code in patterns and replacements, like `(?{ })`.
+    #[serde(default = "default_allow")]
+    pub allow_code_blocks: bool,
+    /// Allow statements after an expression.
+    #[serde(default = "default_allow")]
+    pub allow_statements: bool,
+    /// Evaluate expressions in a Safe compartment.
+    #[serde(default = "default_safe")]
+    pub safe: bool,
+    /// Replaces the default opcodes the compartment permits.
+    pub safe_opcodes: Option<Vec<String>>,
+    /// Opcodes permitted on top of the default ones.
+    #[serde(default)]
+    pub safe_extra_opcodes: Vec<String>,
+    /// What the compartment ends up permitting, `None` without one.
+    #[serde(skip)]
+    pub opcodes: Option<Vec<String>>,
+    /// Run plain substitutions in-process instead of in perl.
+    #[serde(default = "default_native_subst")]
+    pub native_subst: bool,
+    /// Time an in-process run may take before it's handed to perl instead.
+    #[serde(default = "default_native_budget_ms")]
+    pub native_budget_ms: u64,
+    #[serde(default)]
+    pub sandbox: SandboxKind,
+    /// Install the seccomp syscall allowlist in the sandbox.
+    #[serde(default = "default_seccomp")]
+    pub seccomp: bool,
+    /// Replaces the default seccomp allowlist.
+    pub seccomp_syscalls: Option<Vec<String>>,
+    /// Syscalls allowed on top of the allowlist.
+    #[serde(default)]
+    pub seccomp_extra_syscalls: Vec<String>,
+    /// Put every run into its own cgroup, if there is a delegated subtree.
+    #[serde(default = "default_cgroups")]
+    pub cgroups: bool,
+    /// Delegated subtree to use instead of the bot's own cgroup.
+    pub cgroup_root: Option<PathBuf>,
+    /// Limits for line mode, `LINE_`-prefixed variables override the shared
+    /// ones.
+    #[serde(skip)]
+    pub line_limits: Limits,
+    /// Limits for the modes that read the whole text, `;full`, `;para`,
+    /// `;words` and `;chars`. `FULL_`-prefixed variables override the shared
+    /// ones.
+    #[serde(skip)]
+    pub full_limits: Limits,
+    // set by Nix
+    pub bwrap: Option<PathBuf>,
+    pub nsjail: Option<PathBuf>,
+    pub perl: PathBuf,
+    /// Mounted read-only into the sandbox. With `discover_mounts`, on top of
+    /// whatever perl turns out to need.
+    #[serde(default)]
+    pub allow_dirs: Vec<PathBuf>,
+    /// Work out perl's mounts from its dynamic dependencies and `@INC`.
+    #[serde(default = "default_discover_mounts")]
+    pub discover_mounts: bool,
+}
+
+fn default_max_parallel() -> usize {
+    16
+}
+
+fn default_pool_prestart() -> usize {
+    4
+}
+
+fn default_worker_max_jobs() -> u64 {
+    100
+}
+
+fn default_max_output_bytes() -> usize {
+    4096
+}
+
+fn default_allow() -> bool {
+    true
+}
+
+fn default_safe() -> bool {
+    true
+}
+
+fn default_native_subst() -> bool {
+    true
+}
+
+fn default_native_budget_ms() -> u64 {
+    50
+}
+
+fn default_seccomp() -> bool {
+    true
+}
+
+fn default_cgroups() -> bool {
+    true
+}
+
+fn default_discover_mounts() -> bool {
+    true
+}
+
+impl Config {
+    pub fn from_env() -> eyre::Result<Self> {
+        let mut cfg: Self = envy::from_env()?;
+        let limits: Limits = envy::from_env()?;
+        let line: LimitOverrides = envy::prefixed("LINE_").from_env()?;
+        let full: LimitOverrides = envy::prefixed("FULL_").from_env()?;
+        cfg.line_limits = limits.with(&line);
+        cfg.full_limits = limits.with(&full);
+        if cfg.safe {
+            let mut opcodes = cfg
+                .safe_opcodes
+                .clone()
+                .unwrap_or_else(|| DEFAULT_OPCODES.iter().map(|&op| op.to_owned()).collect());
+            opcodes.extend(cfg.safe_extra_opcodes.iter().cloned());
+            ensure!(
+                opcodes
+                    .iter()
+                    .all(|op| !op.is_empty() && !op.contains([',', ' '])),
+                "invalid opcode names in {opcodes:?}",
+            );
+            cfg.opcodes = Some(opcodes);
+        }
+        if cfg.discover_mounts {
+            cfg.allow_dirs = sandbox::discover_mounts(&cfg)?;
+        }
+        Ok(cfg)
+    }
+
+    /// What expressions may do in any chat.
+    pub fn policy(&self) -> Policy {
+        Policy {
+            eval: self.allow_eval,
+            code_blocks: self.allow_code_blocks,
+            statements: self.allow_statements,
+        }
+    }
+
+    pub fn limits(&self, mode: Mode) -> &Limits {
+        match mode {
+            Mode::Line => &self.line_limits,
+            Mode::Full | Mode::Paragraph | Mode::Words | Mode::Chars => &self.full_limits,
+        }
+    }
+}
@@ -0,0 +1,556 @@
+use std::future::Future;
+
+use color_eyre::eyre::{self, eyre};
+use serde::{Deserialize, Serialize};
+use teloxide::{
+    prelude::{Request, Requester},
+    types::{ChatId, InputFile, Message, MessageEntity, MessageId, ParseMode},
+    ApiError, Bot, RequestError,
+};
+
+/// Telegram refuses messages longer than this many characters, counted in
+/// UTF-16 code units like entity offsets.
+const MESSAGE_LIMIT: usize = 4096;
+
+/// Telegram refuses captions longer than this many UTF-16 code units.
+const CAPTION_LIMIT: usize = 1024;
+
+/// How results that don't fit into a single message are delivered.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryMode {
+    /// Several replies, split at line boundaries.
+    #[default]
+    Split,
+    /// A single `.txt` document.
+    Document,
+    /// Several replies plus the document.
+    Both,
+}
+
+impl DeliveryMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "split" => Some(Self::Split),
+            "document" => Some(Self::Document),
+            "both" => Some(Self::Both),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Split => "split",
+            Self::Document => "document",
+            Self::Both => "both",
+        }
+    }
+}
+
+/// Text of a result and the formatting to send it with.
+#[derive(Debug, Clone, Default)]
+pub struct Reply {
+    pub text: String,
+    /// Offsets are in UTF-16 code units, like Telegram's.
+    pub entities: Vec<MessageEntity>,
+    /// Markup for Telegram to parse instead of `entities`.
+    pub parse_mode: Option<ParseMode>,
+}
+
+impl Reply {
+    pub fn plain(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            entities: Vec::new(),
+            parse_mode: None,
+        }
+    }
+
+    /// Adds `note` on a line of its own, escaped for the markup if there is
+    /// any.
+    pub fn note(&mut self, note: &str) {
+        if !self.text.is_empty() && !self.text.ends_with('\n') {
+            self.text.push('\n');
+        }
+        for c in note.chars() {
+            match self.parse_mode {
+                Some(ParseMode::MarkdownV2) if "_*[]()~`>#+-=|{}.!\\".contains(c) => {
+                    self.text.push('\\');
+                    self.text.push(c);
+                }
+                Some(ParseMode::Html) if c == '<' => self.text.push_str("&lt;"),
+                Some(ParseMode::Html) if c == '>' => self.text.push_str("&gt;"),
+                Some(ParseMode::Html) if c == '&' => self.text.push_str("&amp;"),
+                _ => self.text.push(c),
+            }
+        }
+    }
+
+    /// Entities to send along, `None` rather than an empty list.
+    fn entities(&self) -> Option<Vec<MessageEntity>> {
+        (!self.entities.is_empty()).then(|| self.entities.clone())
+    }
+}
+
+/// How a result is formatted, chosen with `;md`, `;html` or `;code`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Format {
+    /// As it is, with the formatting of the message it was run on.
+    #[default]
+    Plain,
+    /// Markup for Telegram to parse.
+    Markup(ParseMode),
+    /// A monospace block, highlighted as the language if there is one.
+    Code(Option<String>),
+}
+
+impl Format {
+    pub fn apply(&self, text: String) -> Reply {
+        match self {
+            Self::Plain => Reply::plain(text),
+            Self::Markup(parse_mode) => Reply {
+                parse_mode: Some(*parse_mode),
+                ..Reply::plain(text)
+            },
+            Self::Code(language) => {
+                let mut text = text;
+                if text.ends_with('\n') {
+                    text.pop();
+                }
+                let len = text.encode_utf16().count();
+                Reply {
+                    entities: vec![MessageEntity::pre(language.clone(), 0, len)],
+                    ..Reply::plain(text)
+                }
+            }
+        }
+    }
+}
+
+/// Whether Telegram refused the markup of a message. Its description
+/// usually says where, which doesn't match the known error.
+fn bad_markup(err: &RequestError) -> bool {
+    match err {
+        RequestError::Api(ApiError::CantParseEntities) => true,
+        RequestError::Api(ApiError::Unknown(description)) => {
+            description.contains("can't parse entities")
+        }
+        _ => false,
+    }
+}
+
+/// Sends `reply` with `send`, and again as plain text if Telegram refuses
+/// its markup.
+async fn with_plain_fallback<T, F>(
+    reply: Reply,
+    send: impl Fn(Reply) -> F,
+) -> Result<T, RequestError>
+where
+    F: Future<Output = Result<T, RequestError>>,
+{
+    let plain = reply.parse_mode.map(|_| Reply::plain(reply.text.clone()));
+    match (send(reply).await, plain) {
+        (Err(err), Some(plain)) if bad_markup(&err) => {
+            tracing::debug!(%err, "markup refused, sending it as plain text");
+            send(plain).await
+        }
+        (res, _) => res,
+    }
+}
+
+/// Messages sent in response to a single command, stored so that edits of
+/// the command can update them.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Delivered {
+    texts: Vec<i32>,
+    document: Option<i32>,
+    #[serde(default)]
+    media: Option<i32>,
+}
+
+impl Delivered {
+    fn decode(raw: &[u8]) -> eyre::Result<Self> {
+        // Before multi-message delivery only the ID of the single reply was
+        // stored.
+        if let Ok(id) = <[u8; 4]>::try_from(raw) {
+            return Ok(Self {
+                texts: vec![i32::from_le_bytes(id)],
+                ..Self::default()
+            });
+        }
+
+        Ok(serde_json::from_slice(raw)?)
+    }
+
+    fn ids(&self) -> impl Iterator<Item = MessageId> + '_ {
+        self.texts
+            .iter()
+            .chain(&self.document)
+            .chain(&self.media)
+            .map(|&id| MessageId(id))
+    }
+}
+
+fn unique_id(message: &Message) -> [u8; 12] {
+    let mut res = [0; 12];
+    res[..8].copy_from_slice(&message.chat.id.0.to_le_bytes());
+    res[8..].copy_from_slice(&message.id.0.to_le_bytes());
+    res
+}
+
+/// Splits `text` into chunks of at most `limit` UTF-16 code units,
+/// preferring line boundaries and falling back to character boundaries for
+/// overlong lines.
+fn split_text(text: &str, limit: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut cur = String::new();
+    let mut cur_len = 0;
+    for line in text.split_inclusive('\n') {
+        let line_len = line.encode_utf16().count();
+        if cur_len + line_len > limit && !cur.is_empty() {
+            chunks.push(std::mem::take(&mut cur));
+            cur_len = 0;
+        }
+
+        if line_len <= limit {
+            cur.push_str(line);
+            cur_len += line_len;
+            continue;
+        }
+
+        for ch in line.chars() {
+            if cur_len + ch.len_utf16() > limit {
+                chunks.push(std::mem::take(&mut cur));
+                cur_len = 0;
+            }
+            cur.push(ch);
+            cur_len += ch.len_utf16();
+        }
+    }
+    if !cur.is_empty() {
+        chunks.push(cur);
+    }
+    chunks
+}
+
+/// Splits `reply` like [`split_text`], cutting its entities at the chunk
+/// boundaries.
+fn split_reply(reply: &Reply, limit: usize) -> Vec<Reply> {
+    let mut start = 0;
+    split_text(&reply.text, limit)
+        .into_iter()
+        .map(|text| {
+            let end = start + text.encode_utf16().count();
+            let entities = reply
+                .entities
+                .iter()
+                .filter_map(|entity| {
+                    let from = entity.offset.max(start);
+                    let to = (entity.offset + entity.length).min(end);
+                    (from < to).then(|| MessageEntity {
+                        offset: from - start,
+                        length: to - from,
+                        ..entity.clone()
+                    })
+                })
+                .collect();
+            start = end;
+            Reply {
+                text,
+                entities,
+                parse_mode: reply.parse_mode,
+            }
+        })
+        .collect()
+}
+
+/// What should be sent for a result.
+#[derive(Default)]
+struct Plan {
+    texts: Vec<Reply>,
+    /// Documents are plain text, without the formatting. Markup is sent as
+    /// its source.
+    document: Option<String>,
+    /// Caption for a copy of the media message the command replied to.
+    media: Option<Reply>,
+}
+
+impl Plan {
+    fn new(reply: &Reply, mode: DeliveryMode, media: bool) -> Self {
+        let len = reply.text.encode_utf16().count();
+        if media && len <= CAPTION_LIMIT {
+            return Self {
+                media: Some(reply.clone()),
+                ..Self::default()
+            };
+        }
+        if len <= MESSAGE_LIMIT {
+            return Self {
+                texts: vec![reply.clone()],
+                ..Self::default()
+            };
+        }
+        // Splitting markup would cut its tags and escapes apart.
+        if reply.parse_mode.is_some() {
+            return Self {
+                document: Some(reply.text.clone()),
+                ..Self::default()
+            };
+        }
+
+        match mode {
+            DeliveryMode::Split => Self {
+                texts: split_reply(reply, MESSAGE_LIMIT),
+                ..Self::default()
+            },
+            DeliveryMode::Document => Self {
+                document: Some(reply.text.clone()),
+                ..Self::default()
+            },
+            DeliveryMode::Both => Self {
+                texts: split_reply(reply, MESSAGE_LIMIT),
+                document: Some(reply.text.clone()),
+                ..Self::default()
+            },
+        }
+    }
+}
+
+/// Sends results as replies and keeps track of them so that edits of the
+/// command edit the results too.
+#[derive(Clone)]
+pub struct Delivery {
+    bot: Bot,
+    db: sled::Db,
+}
+
+impl Delivery {
+    pub fn new(bot: Bot, db: sled::Db) -> Self {
+        Self { bot, db }
+    }
+
+    /// Delivers `reply` as the result of `command`, which replied to
+    /// `reply_to`. With `media`, `reply_to` is sent again with `reply` as
+    /// its caption, if it fits into one.
+    pub async fn deliver(
+        &self,
+        command: &Message,
+        reply_to: &Message,
+        reply: &Reply,
+        edited: bool,
+        mode: DeliveryMode,
+        media: bool,
+    ) -> eyre::Result<()> {
+        let plan = Plan::new(reply, mode, media);
+        let key = unique_id(command);
+
+        let delivered = if edited {
+            let raw = self
+                .db
+                .get(key)?
+                .ok_or_else(|| eyre!("original message {} not found in db", command.id))?;
+            let old = Delivered::decode(&raw)?;
+            self.update(reply_to, old, plan).await?
+        } else {
+            self.send(reply_to, plan).await?
+        };
+
+        self.db.insert(key, serde_json::to_vec(&delivered)?)?;
+        Ok(())
+    }
+
+    async fn send_text(&self, reply_to: &Message, reply: &Reply) -> Result<Message, RequestError> {
+        let mut request = self.bot.send_message(reply_to.chat.id, &reply.text);
+        request.entities = reply.entities();
+        request.parse_mode = reply.parse_mode;
+        request.reply_to_message_id = Some(reply_to.id);
+        request.send().await
+    }
+
+    async fn send_media(
+        &self,
+        reply_to: &Message,
+        reply: &Reply,
+    ) -> Result<MessageId, RequestError> {
+        let chat_id = reply_to.chat.id;
+        let mut request = self.bot.copy_message(chat_id, chat_id, reply_to.id);
+        request.caption = Some(reply.text.clone());
+        request.caption_entities = reply.entities();
+        request.parse_mode = reply.parse_mode;
+        request.reply_to_message_id = Some(reply_to.id);
+        request.send().await
+    }
+
+    /// Edits a sent result, the text of a message or the caption of a
+    /// media one.
+    async fn edit(
+        &self,
+        chat_id: ChatId,
+        id: i32,
+        reply: &Reply,
+        caption: bool,
+    ) -> Result<(), RequestError> {
+        if caption {
+            let mut request = self.bot.edit_message_caption(chat_id, MessageId(id));
+            request.caption = Some(reply.text.clone());
+            request.caption_entities = reply.entities();
+            request.parse_mode = reply.parse_mode;
+            request.send().await.map(drop)
+        } else {
+            let mut request = self
+                .bot
+                .edit_message_text(chat_id, MessageId(id), &reply.text);
+            request.entities = reply.entities();
+            request.parse_mode = reply.parse_mode;
+            request.send().await.map(drop)
+        }
+    }
+
+    async fn send(&self, reply_to: &Message, plan: Plan) -> eyre::Result<Delivered> {
+        let mut delivered = Delivered::default();
+        for reply in plan.texts {
+            let message = with_plain_fallback(reply, |reply| async move {
+                self.send_text(reply_to, &reply).await
+            })
+            .await?;
+            delivered.texts.push(message.id.0);
+        }
+
+        if let Some(reply) = plan.media {
+            let id = with_plain_fallback(reply, |reply| async move {
+                self.send_media(reply_to, &reply).await
+            })
+            .await?;
+            delivered.media = Some(id.0);
+        }
+
+        if let Some(text) = plan.document {
+            let file = InputFile::memory(text.into_bytes()).file_name("result.txt");
+            let mut request = self.bot.send_document(reply_to.chat.id, file);
+            request.reply_to_message_id = Some(reply_to.id);
+            delivered.document = Some(request.send().await?.id.0);
+        }
+
+        Ok(delivered)
+    }
+
+    /// Edits previously sent results in place when the shape of the reply
+    /// didn't change, otherwise replaces them.
+    async fn update(
+        &self,
+        reply_to: &Message,
+        old: Delivered,
+        plan: Plan,
+    ) -> eyre::Result<Delivered> {
+        let chat_id = reply_to.chat.id;
+        if old.document.is_none()
+            && plan.document.is_none()
+            && old.texts.len() == plan.texts.len()
+            && old.media.is_some() == plan.media.is_some()
+        {
+            let edits = old
+                .texts
+                .iter()
+                .copied()
+                .zip(plan.texts)
+                .map(|(id, reply)| (id, reply, false))
+                .chain(
+                    old.media
+                        .zip(plan.media)
+                        .map(|(id, reply)| (id, reply, true)),
+                );
+            for (id, reply, caption) in edits {
+                let res = with_plain_fallback(reply, |reply| async move {
+                    self.edit(chat_id, id, &reply, caption).await
+                })
+                .await;
+                if let Err(err) = res {
+                    if !matches!(err, RequestError::Api(ApiError::MessageNotModified)) {
+                        return Err(err.into());
+                    }
+                }
+            }
+            return Ok(old);
+        }
+
+        for id in old.ids() {
+            if let Err(err) = self.bot.delete_message(chat_id, id).send().await {
+                tracing::warn!(%err, message = id.0, "failed to delete outdated result");
+            }
+        }
+        self.send(reply_to, plan).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_at_lines() {
+        assert_eq!(split_text("ab\ncd\nef\n", 6), ["ab\ncd\n", "ef\n"]);
+        assert_eq!(split_text("ab\ncd", 4), ["ab\n", "cd"]);
+        assert_eq!(split_text("short", 10), ["short"]);
+        assert!(split_text("", 10).is_empty());
+    }
+
+    #[test]
+    fn splits_long_lines() {
+        assert_eq!(split_text("abcdefg\nh", 3), ["abc", "def", "g\nh"]);
+        assert_eq!(split_text("ab\ncdefg", 3), ["ab\n", "cde", "fg"]);
+    }
+
+    #[test]
+    fn counts_utf16() {
+        // Two code units each.
+        assert_eq!(split_text("😀😀😀", 4), ["😀😀", "😀"]);
+        assert_eq!(split_text("😀😀😀", 3), ["😀", "😀", "😀"]);
+        assert_eq!(split_text("é😀\n😀", 4), ["é😀\n", "😀"]);
+    }
+
+    #[test]
+    fn cuts_entities_at_chunks() {
+        let reply = Reply {
+            entities: vec![MessageEntity::bold(2, 5), MessageEntity::italic(6, 2)],
+            ..Reply::plain("😀 ab\ncd\n")
+        };
+        let chunks = split_reply(&reply, 6);
+        let texts: Vec<_> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        assert_eq!(texts, ["😀 ab\n", "cd\n"]);
+        assert_eq!(chunks[0].entities, [MessageEntity::bold(2, 4)]);
+        assert_eq!(
+            chunks[1].entities,
+            [MessageEntity::bold(0, 1), MessageEntity::italic(0, 2)]
+        );
+    }
+
+    #[test]
+    fn markup_is_not_split() {
+        let reply = Reply {
+            parse_mode: Some(ParseMode::Html),
+            ..Reply::plain("<b>a</b>\n".repeat(MESSAGE_LIMIT))
+        };
+        let plan = Plan::new(&reply, DeliveryMode::Split, false);
+        assert!(plan.texts.is_empty());
+        assert_eq!(plan.document, Some(reply.text));
+    }
+
+    #[test]
+    fn notes_are_escaped() {
+        for (format, text) in [
+            (Format::Plain, "a\n[output truncated]"),
+            (
+                Format::Markup(ParseMode::MarkdownV2),
+                "a\n\\[output truncated\\]",
+            ),
+            (Format::Code(None), "a\n[output truncated]"),
+        ] {
+            let mut reply = format.apply("a\n".to_owned());
+            reply.note("[output truncated]");
+            assert_eq!(reply.text, text, "{format:?}");
+        }
+        let mut reply = Format::Markup(ParseMode::Html).apply("a".to_owned());
+        reply.note("<&>");
+        assert_eq!(reply.text, "a\n&lt;&amp;&gt;");
+    }
+}
@@ -0,0 +1,647 @@
+//! Word and line diffs between a message and its result, for `;diff` and
+//! to carry the formatting of the message over to the result.
+
+use teloxide::types::{MessageEntity, MessageEntityKind};
+
+use crate::delivery::Reply;
+
+/// Past this many cells of the common subsequence table the whole changed
+/// middle is shown as replaced instead.
+const MAX_CELLS: usize = 1 << 22;
+
+/// When to reply with a diff instead of the result.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiffMode {
+    /// When the result is long, see `Config::auto_diff_chars`.
+    #[default]
+    Auto,
+    Always(Granularity),
+    Never,
+}
+
+/// What a diff compares.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Granularity {
+    #[default]
+    Words,
+    Lines,
+}
+
+impl Granularity {
+    /// Unchanged tokens kept on each side of a change.
+    fn context(self) -> usize {
+        match self {
+            // Words and the whitespace between them.
+            Self::Words => 8,
+            Self::Lines => 2,
+        }
+    }
+
+    /// Splits `text` into tokens that concatenate back to it.
+    fn tokenize(self, text: &str) -> Vec<&str> {
+        match self {
+            Self::Words => {
+                let mut tokens = Vec::new();
+                let mut start = 0;
+                let mut prev_space = None;
+                for (idx, c) in text.char_indices() {
+                    let space = c.is_whitespace();
+                    if prev_space.is_some_and(|prev| prev != space) {
+                        tokens.push(&text[start..idx]);
+                        start = idx;
+                    }
+                    prev_space = Some(space);
+                }
+                if start < text.len() {
+                    tokens.push(&text[start..]);
+                }
+                tokens
+            }
+            Self::Lines => text.split_inclusive('\n').collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op<'a> {
+    Same(&'a str),
+    Deleted(&'a str),
+    Inserted(&'a str),
+}
+
+/// Edits from `old` to `new` along their longest common subsequence.
+fn ops<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Op<'a>> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+
+    let mut ops: Vec<_> = old[..prefix].iter().map(|&token| Op::Same(token)).collect();
+    if a.len().saturating_mul(b.len()) > MAX_CELLS {
+        ops.extend(a.iter().map(|&token| Op::Deleted(token)));
+        ops.extend(b.iter().map(|&token| Op::Inserted(token)));
+    } else {
+        // `lcs[i][j]` is the length of the longest common subsequence of
+        // `a[i..]` and `b[j..]`.
+        let width = b.len() + 1;
+        let mut lcs = vec![0u32; (a.len() + 1) * width];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i * width + j] = if a[i] == b[j] {
+                    lcs[(i + 1) * width + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && a[i] == b[j] {
+                ops.push(Op::Same(a[i]));
+                i += 1;
+                j += 1;
+            } else if j == b.len()
+                || (i < a.len() && lcs[(i + 1) * width + j] >= lcs[i * width + j + 1])
+            {
+                ops.push(Op::Deleted(a[i]));
+                i += 1;
+            } else {
+                ops.push(Op::Inserted(b[j]));
+                j += 1;
+            }
+        }
+    }
+    ops.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|&token| Op::Same(token)),
+    );
+    ops
+}
+
+/// Stretch of a diff.
+#[derive(Debug)]
+enum Chunk<'a> {
+    Same(Vec<&'a str>),
+    /// Everything deleted and inserted between two unchanged stretches.
+    Changed {
+        deleted: String,
+        inserted: String,
+    },
+}
+
+fn chunks(ops: Vec<Op<'_>>) -> Vec<Chunk<'_>> {
+    let mut chunks: Vec<Chunk<'_>> = Vec::new();
+    for op in ops {
+        match (op, chunks.last_mut()) {
+            (Op::Same(token), Some(Chunk::Same(tokens))) => tokens.push(token),
+            (Op::Same(token), _) => chunks.push(Chunk::Same(vec![token])),
+            (Op::Deleted(token), Some(Chunk::Changed { deleted, .. })) => deleted.push_str(token),
+            (Op::Inserted(token), Some(Chunk::Changed { inserted, .. })) => {
+                inserted.push_str(token)
+            }
+            (Op::Deleted(token), _) => chunks.push(Chunk::Changed {
+                deleted: token.to_owned(),
+                inserted: String::new(),
+            }),
+            (Op::Inserted(token), _) => chunks.push(Chunk::Changed {
+                deleted: String::new(),
+                inserted: token.to_owned(),
+            }),
+        }
+    }
+
+    // Whitespace between two changes reads better as part of both, e.g.
+    // "a b" → "x y" instead of "a" → "x", "b" → "y".
+    let mut merged: Vec<Chunk<'_>> = Vec::with_capacity(chunks.len());
+    let mut chunks = chunks.into_iter().peekable();
+    while let Some(chunk) = chunks.next() {
+        match chunk {
+            Chunk::Same(tokens)
+                if tokens.iter().all(|token| token.trim().is_empty())
+                    && matches!(merged.last(), Some(Chunk::Changed { .. }))
+                    && matches!(chunks.peek(), Some(Chunk::Changed { .. })) =>
+            {
+                let space = tokens.concat();
+                let Some(Chunk::Changed {
+                    deleted: next_deleted,
+                    inserted: next_inserted,
+                }) = chunks.next()
+                else {
+                    unreachable!()
+                };
+                let Some(Chunk::Changed { deleted, inserted }) = merged.last_mut() else {
+                    unreachable!()
+                };
+                for (part, next) in [(deleted, next_deleted), (inserted, next_inserted)] {
+                    part.push_str(&space);
+                    part.push_str(&next);
+                }
+            }
+            chunk => merged.push(chunk),
+        }
+    }
+    merged
+}
+
+/// Reply text with its entities, offsets counted in UTF-16 like Telegram
+/// does.
+#[derive(Default)]
+struct Builder {
+    reply: Reply,
+    len: usize,
+}
+
+impl Builder {
+    fn push(&mut self, text: &str) {
+        self.reply.text.push_str(text);
+        self.len += text.encode_utf16().count();
+    }
+
+    fn push_entity(&mut self, text: &str, entity: fn(usize, usize) -> MessageEntity) {
+        let offset = self.len;
+        self.push(text);
+        if self.len > offset {
+            self.reply.entities.push(entity(offset, self.len - offset));
+        }
+    }
+
+    /// Pushes an unchanged stretch, eliding all but `context` tokens next
+    /// to the changes before and after it.
+    fn push_same(&mut self, tokens: &[&str], granularity: Granularity, before: bool, after: bool) {
+        let context = granularity.context();
+        let (keep_head, keep_tail) = match (before, after) {
+            (true, true) => (context, context),
+            (true, false) => (context, 0),
+            (false, true) => (0, context),
+            (false, false) => (tokens.len(), 0),
+        };
+        if keep_head + keep_tail >= tokens.len() {
+            self.push(&tokens.concat());
+            return;
+        }
+
+        let head = tokens[..keep_head].concat();
+        let tail = tokens[tokens.len() - keep_tail..].concat();
+        match granularity {
+            Granularity::Words => {
+                let head = head.trim_end();
+                let tail = tail.trim_start();
+                self.push(head);
+                self.push(if head.is_empty() { "…" } else { " …" });
+                if !tail.is_empty() {
+                    self.push(" ");
+                    self.push(tail);
+                }
+            }
+            Granularity::Lines => {
+                self.push(&head);
+                self.push(if tail.is_empty() { "…" } else { "…\n" });
+                self.push(&tail);
+            }
+        }
+    }
+}
+
+/// `new` without the newline perl ends its output with, unless `old` ended
+/// with one too.
+fn trim_result<'a>(old: &str, new: &'a str) -> &'a str {
+    if old.ends_with('\n') {
+        new
+    } else {
+        new.strip_suffix('\n').unwrap_or(new)
+    }
+}
+
+/// Diff from the text of a message to the result of running expressions on
+/// it, deletions struck through and insertions in bold, or `None` if
+/// nothing changed.
+fn render(old: &str, new: &str, granularity: Granularity) -> Option<Reply> {
+    let new = trim_result(old, new);
+    if old == new {
+        return None;
+    }
+
+    let old_tokens = granularity.tokenize(old);
+    let new_tokens = granularity.tokenize(new);
+    let chunks = chunks(ops(&old_tokens, &new_tokens));
+    let mut builder = Builder::default();
+    for (idx, chunk) in chunks.iter().enumerate() {
+        match chunk {
+            Chunk::Same(tokens) => {
+                builder.push_same(tokens, granularity, idx > 0, idx + 1 < chunks.len())
+            }
+            Chunk::Changed { deleted, inserted } => {
+                builder.push_entity(deleted, MessageEntity::strikethrough);
+                builder.push_entity(inserted, MessageEntity::bold);
+            }
+        }
+    }
+    Some(builder.reply)
+}
+
+/// Stretch of text that is either unchanged or replaced, lengths in UTF-16
+/// code units.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    old: usize,
+    new: usize,
+    same: bool,
+}
+
+fn push_segment(segments: &mut Vec<Segment>, op: Op<'_>) {
+    let (Op::Same(text) | Op::Deleted(text) | Op::Inserted(text)) = op;
+    if text.is_empty() {
+        return;
+    }
+    let len = text.encode_utf16().count();
+    let segment = match op {
+        Op::Same(_) => Segment {
+            old: len,
+            new: len,
+            same: true,
+        },
+        Op::Deleted(_) => Segment {
+            old: len,
+            new: 0,
+            same: false,
+        },
+        Op::Inserted(_) => Segment {
+            old: 0,
+            new: len,
+            same: false,
+        },
+    };
+    match segments.last_mut() {
+        Some(last) if last.same == segment.same => {
+            last.old += segment.old;
+            last.new += segment.new;
+        }
+        _ => segments.push(segment),
+    }
+}
+
+/// How `old` turned into `new`: a word diff, where each changed stretch
+/// keeps the characters it starts and ends with in common.
+fn segments(old: &str, new: &str) -> Vec<Segment> {
+    fn flush(segments: &mut Vec<Segment>, deleted: &mut String, inserted: &mut String) {
+        let prefix: usize = deleted
+            .chars()
+            .zip(inserted.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(c, _)| c.len_utf8())
+            .sum();
+        let suffix: usize = deleted[prefix..]
+            .chars()
+            .rev()
+            .zip(inserted[prefix..].chars().rev())
+            .take_while(|(a, b)| a == b)
+            .map(|(c, _)| c.len_utf8())
+            .sum();
+        push_segment(segments, Op::Same(&deleted[..prefix]));
+        push_segment(
+            segments,
+            Op::Deleted(&deleted[prefix..deleted.len() - suffix]),
+        );
+        push_segment(
+            segments,
+            Op::Inserted(&inserted[prefix..inserted.len() - suffix]),
+        );
+        push_segment(segments, Op::Same(&deleted[deleted.len() - suffix..]));
+        deleted.clear();
+        inserted.clear();
+    }
+
+    let words = Granularity::Words;
+    let mut segments = Vec::new();
+    let (mut deleted, mut inserted) = (String::new(), String::new());
+    for op in ops(&words.tokenize(old), &words.tokenize(new)) {
+        match op {
+            Op::Same(_) => {
+                flush(&mut segments, &mut deleted, &mut inserted);
+                push_segment(&mut segments, op);
+            }
+            Op::Deleted(token) => deleted.push_str(token),
+            Op::Inserted(token) => inserted.push_str(token),
+        }
+    }
+    flush(&mut segments, &mut deleted, &mut inserted);
+    segments
+}
+
+/// Where an entity starting at `offset` of the old text starts in the new
+/// one. Replacements of its first characters are part of it, insertions
+/// right before it are not.
+fn map_start(segments: &[Segment], offset: usize) -> usize {
+    let (mut old, mut new) = (0, 0);
+    for segment in segments {
+        if offset < old + segment.old {
+            return if segment.same {
+                new + offset - old
+            } else {
+                new
+            };
+        }
+        old += segment.old;
+        new += segment.new;
+    }
+    new
+}
+
+/// Where an entity ending at `offset` of the old text ends in the new one,
+/// the counterpart of [`map_start`].
+fn map_end(segments: &[Segment], offset: usize) -> usize {
+    let (mut old, mut new) = (0, 0);
+    for segment in segments {
+        if offset <= old {
+            return new;
+        }
+        if offset <= old + segment.old {
+            return if segment.same {
+                new + offset - old
+            } else {
+                new + segment.new
+            };
+        }
+        old += segment.old;
+        new += segment.new;
+    }
+    new
+}
+
+/// Entities Telegram finds in the text on its own, and custom emoji, which
+/// only make sense on the emoji they were sent with.
+fn detected(kind: &MessageEntityKind) -> bool {
+    matches!(
+        kind,
+        MessageEntityKind::Mention
+            | MessageEntityKind::Hashtag
+            | MessageEntityKind::Cashtag
+            | MessageEntityKind::BotCommand
+            | MessageEntityKind::Url
+            | MessageEntityKind::Email
+            | MessageEntityKind::PhoneNumber
+            | MessageEntityKind::CustomEmoji { .. }
+    )
+}
+
+/// Moves the formatting of `old` onto `new`, the result of running
+/// expressions on it. Text that replaced formatted text takes on its
+/// formatting, text inserted at the edge of an entity doesn't.
+fn carry_entities(old: &str, new: &str, entities: &[MessageEntity]) -> Vec<MessageEntity> {
+    if entities.iter().all(|entity| detected(&entity.kind)) {
+        return Vec::new();
+    }
+
+    let segments = segments(old, trim_result(old, new));
+    entities
+        .iter()
+        .filter(|entity| !detected(&entity.kind))
+        .filter_map(|entity| {
+            let start = map_start(&segments, entity.offset);
+            let end = map_end(&segments, entity.offset + entity.length);
+            (start < end).then(|| MessageEntity {
+                offset: start,
+                length: end - start,
+                ..entity.clone()
+            })
+        })
+        .collect()
+}
+
+/// What to reply with for `new`, the result of running expressions on
+/// `old`, which had the formatting `entities`.
+pub fn reply(
+    old: &str,
+    entities: &[MessageEntity],
+    new: String,
+    mode: DiffMode,
+    auto_diff_chars: usize,
+) -> Reply {
+    let carried = |new: String| Reply {
+        entities: carry_entities(old, &new, entities),
+        ..Reply::plain(new)
+    };
+    match mode {
+        DiffMode::Always(granularity) => {
+            render(old, &new, granularity).unwrap_or_else(|| Reply::plain("no changes"))
+        }
+        DiffMode::Auto if auto_diff_chars > 0 && new.chars().count() > auto_diff_chars => {
+            match render(old, &new, Granularity::default()) {
+                Some(diff) if diff.text.len() < new.len() => diff,
+                _ => carried(new),
+            }
+        }
+        DiffMode::Auto | DiffMode::Never => carried(new),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text of `new` that `entity` covers.
+    fn covered(new: &str, entity: &MessageEntity) -> String {
+        let units: Vec<u16> = new.encode_utf16().collect();
+        String::from_utf16(&units[entity.offset..entity.offset + entity.length]).unwrap()
+    }
+
+    fn carried(old: &str, new: &str, entity: MessageEntity) -> Vec<String> {
+        carry_entities(old, new, &[entity])
+            .iter()
+            .map(|entity| covered(new, entity))
+            .collect()
+    }
+
+    #[test]
+    fn replaced_word_keeps_formatting() {
+        assert_eq!(
+            carried("say teh word", "say the word\n", MessageEntity::bold(4, 3)),
+            ["the"]
+        );
+        assert_eq!(
+            carried(
+                "the quick brown fox",
+                "the quick red fox\n",
+                MessageEntity::italic(4, 11)
+            ),
+            ["quick red"]
+        );
+    }
+
+    #[test]
+    fn insertions_at_the_edge_stay_outside() {
+        assert_eq!(
+            carried("hello world", ">> hello world\n", MessageEntity::bold(6, 5)),
+            ["world"]
+        );
+        assert_eq!(
+            carried("hello world", "hello world!\n", MessageEntity::bold(6, 5)),
+            ["world"]
+        );
+        assert_eq!(
+            carried("hello world", "hello, world\n", MessageEntity::bold(0, 5)),
+            ["hello"]
+        );
+    }
+
+    #[test]
+    fn deleted_text_loses_its_entity() {
+        assert_eq!(
+            carried("a bold b", "a  b\n", MessageEntity::bold(2, 4)),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            carried("a bold b", "a b\n", MessageEntity::bold(2, 4)),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn offsets_are_utf16() {
+        // The flag takes four code units, the emoji two.
+        let entity = MessageEntity::bold(7, 5);
+        assert_eq!(covered("🇫🇷 x hello", &entity), "hello");
+        assert_eq!(
+            carried("🇫🇷 x hello", "🇫🇷 yy hello\n", entity.clone()),
+            ["hello"]
+        );
+        let moved = carry_entities("🇫🇷 x hello", "🇫🇷 😀 hello\n", std::slice::from_ref(&entity));
+        assert_eq!((moved[0].offset, moved[0].length), (8, 5));
+        assert_eq!(carried("🇫🇷 x hello", "🇫🇷 x hellö\n", entity), ["hellö"]);
+    }
+
+    fn words(count: usize, sep: &str) -> String {
+        (0..count)
+            .map(|idx| format!("w{idx}"))
+            .collect::<Vec<_>>()
+            .join(sep)
+    }
+
+    #[test]
+    fn common_subsequence() {
+        let ops = ops(&["a", " ", "b", " ", "c"], &["a", " ", "x", " ", "c"]);
+        assert_eq!(
+            format!("{ops:?}"),
+            r#"[Same("a"), Same(" "), Deleted("b"), Inserted("x"), Same(" "), Same("c")]"#
+        );
+    }
+
+    #[test]
+    fn unchanged() {
+        assert!(render("a b", "a b\n", Granularity::Words).is_none());
+        assert!(render("a b\n", "a b\n", Granularity::Lines).is_none());
+        let reply = reply(
+            "a b",
+            &[],
+            "a b\n".to_owned(),
+            DiffMode::Always(Granularity::Words),
+            0,
+        );
+        assert_eq!(reply.text, "no changes");
+    }
+
+    #[test]
+    fn single_word() {
+        let diff = render("the quick fox", "the slow fox\n", Granularity::Words).unwrap();
+        assert_eq!(diff.text, "the quickslow fox");
+        assert_eq!(
+            diff.entities,
+            [
+                MessageEntity::strikethrough(4, 5),
+                MessageEntity::bold(9, 4)
+            ]
+        );
+    }
+
+    #[test]
+    fn whitespace_between_changes_is_merged() {
+        let diff = render("a b c", "x y c\n", Granularity::Words).unwrap();
+        assert_eq!(diff.text, "a bx y c");
+        assert_eq!(
+            diff.entities,
+            [
+                MessageEntity::strikethrough(0, 3),
+                MessageEntity::bold(3, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn long_unchanged_stretches_are_elided() {
+        let old = words(30, " ");
+        let diff = render(&old, &old.replace("w15", "X"), Granularity::Words).unwrap();
+        assert_eq!(diff.text, "… w11 w12 w13 w14 w15X w16 w17 w18 w19 …");
+
+        let old = words(30, "\n");
+        let diff = render(&old, &old.replace("w15", "X"), Granularity::Lines).unwrap();
+        assert_eq!(diff.text, "…\nw13\nw14\nw15\nX\nw16\nw17\n…");
+    }
+
+    #[test]
+    fn auto_diff_threshold() {
+        let old = words(30, " ");
+        let new = old.replace("w15", "X") + "\n";
+        let auto = |new: &str, chars| reply(&old, &[], new.to_owned(), DiffMode::Auto, chars);
+        // Off, below the threshold, or not shorter than the result.
+        assert_eq!(auto(&new, 0).text, new);
+        assert_eq!(auto(&new, 1000).text, new);
+        assert_eq!(auto("short\n", 3).text, "short\n");
+        let diff = auto(&new, 10);
+        assert!(diff.text.len() < new.len());
+        assert!(diff.text.contains("w15X"));
+        assert_eq!(reply(&old, &[], new.clone(), DiffMode::Never, 10).text, new);
+    }
+
+    #[test]
+    fn detected_entities_are_dropped() {
+        let url = MessageEntity::new(MessageEntityKind::Url, 0, 15);
+        assert!(carry_entities("https://a.b/c d", "https://a.b/x d\n", &[url]).is_empty());
+    }
+}
@@ -0,0 +1,331 @@
+//! `;name` and `;name=value` lines that change how the expressions of a
+//! message run.
+
+use std::fmt;
+
+use teloxide::types::ParseMode;
+
+use crate::{
+    delivery::Format,
+    diff::{DiffMode, Granularity},
+    perl::Mode,
+};
+
+/// Everything the directives of a message asked for.
+#[derive(Debug, Clone, Default)]
+pub struct Directives {
+    /// What the expressions run on, line by line unless a mode directive
+    /// says otherwise.
+    pub mode: Mode,
+    /// Delete the command message after replying.
+    pub delete: bool,
+    /// Whether to reply with a diff against the message instead.
+    pub diff: DiffMode,
+    /// How to format the result.
+    pub format: Format,
+    /// Send media again with the result as their caption.
+    pub media: bool,
+    /// Reply with the list of directives.
+    pub help: bool,
+}
+
+/// Whether a directive takes a value, with its placeholder for the help.
+#[derive(Clone, Copy)]
+enum Value {
+    None,
+    Optional(&'static str),
+}
+
+/// A directive the bot knows.
+struct Directive {
+    name: &'static str,
+    value: Value,
+    help: &'static str,
+    /// Records the directive, with its value if it takes one.
+    apply: fn(&mut Directives, Option<&str>) -> Result<(), String>,
+}
+
+const REGISTRY: &[Directive] = &[
+    Directive {
+        name: "full",
+        value: Value::None,
+        help: "run on the whole text at once instead of line by line",
+        apply: |directives, _| set_mode(directives, Mode::Full),
+    },
+    Directive {
+        name: "para",
+        value: Value::None,
+        help: "run on each paragraph instead of each line",
+        apply: |directives, _| set_mode(directives, Mode::Paragraph),
+    },
+    Directive {
+        name: "words",
+        value: Value::None,
+        help: "run on each word, keeping the whitespace between them",
+        apply: |directives, _| set_mode(directives, Mode::Words),
+    },
+    Directive {
+        name: "chars",
+        value: Value::None,
+        help: "run on each character",
+        apply: |directives, _| set_mode(directives, Mode::Chars),
+    },
+    Directive {
+        name: "diff",
+        value: Value::Optional("words|lines|off"),
+        help: "reply with what changed instead of the whole result",
+        apply: |directives, value| {
+            if directives.format != Format::Plain && value != Some("off") {
+                return Err("can't be combined with ;md, ;html or ;code".to_owned());
+            }
+            directives.diff = match value {
+                None | Some("words") => DiffMode::Always(Granularity::Words),
+                Some("lines") => DiffMode::Always(Granularity::Lines),
+                Some("off") => DiffMode::Never,
+                Some(value) => return Err(format!("expected words/lines/off, got {value:?}")),
+            };
+            Ok(())
+        },
+    },
+    Directive {
+        name: "md",
+        value: Value::None,
+        help: "send the result as MarkdownV2",
+        apply: |directives, _| set_format(directives, Format::Markup(ParseMode::MarkdownV2)),
+    },
+    Directive {
+        name: "html",
+        value: Value::None,
+        help: "send the result as HTML",
+        apply: |directives, _| set_format(directives, Format::Markup(ParseMode::Html)),
+    },
+    Directive {
+        name: "code",
+        value: Value::Optional("language"),
+        help: "send the result as a code block",
+        apply: |directives, value| {
+            let language = value.filter(|language| !language.is_empty());
+            set_format(directives, Format::Code(language.map(str::to_owned)))
+        },
+    },
+    Directive {
+        name: "media",
+        value: Value::None,
+        help: "send photos, videos and files again with the result as their caption",
+        apply: |directives, _| {
+            directives.media = true;
+            Ok(())
+        },
+    },
+    Directive {
+        name: "del",
+        value: Value::None,
+        help: "delete your message after replying",
+        apply: |directives, _| {
+            directives.delete = true;
+            Ok(())
+        },
+    },
+    Directive {
+        name: "help",
+        value: Value::None,
+        help: "list the directives",
+        apply: |directives, _| {
+            directives.help = true;
+            Ok(())
+        },
+    },
+];
+
+fn set_mode(directives: &mut Directives, mode: Mode) -> Result<(), String> {
+    if directives.mode != Mode::Line && directives.mode != mode {
+        return Err("only one of ;full, ;para, ;words and ;chars can be used".to_owned());
+    }
+    directives.mode = mode;
+    Ok(())
+}
+
+fn set_format(directives: &mut Directives, format: Format) -> Result<(), String> {
+    if directives.format != Format::Plain && directives.format != format {
+        return Err("only one of ;md, ;html and ;code can be used".to_owned());
+    }
+    if let DiffMode::Always(_) = directives.diff {
+        return Err("can't be combined with ;diff".to_owned());
+    }
+    directives.format = format;
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirectiveError {
+    Unknown(String),
+    UnexpectedValue(&'static str),
+    InvalidValue(&'static str, String),
+}
+
+impl fmt::Display for DirectiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown(name) => write!(f, "unknown directive ;{name}, see ;help"),
+            Self::UnexpectedValue(name) => write!(f, ";{name} doesn't take a value"),
+            Self::InvalidValue(name, msg) => write!(f, ";{name}: {msg}"),
+        }
+    }
+}
+
+/// Splits a directive line into its name and value. Lines that don't look
+/// like one, like ";)" or "; a comment", are `None`.
+fn split_line(line: &str) -> Option<(&str, Option<&str>)> {
+    let rest = line.trim_end().strip_prefix(';')?;
+    let name_len = rest
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+        .unwrap_or(rest.len());
+    let (name, rest) = rest.split_at(name_len);
+    if name.is_empty() {
+        return None;
+    }
+    match rest.strip_prefix('=') {
+        Some(value) => Some((name, Some(value))),
+        None if rest.is_empty() => Some((name, None)),
+        None => None,
+    }
+}
+
+impl Directives {
+    /// Collects the directives among the lines of `text`.
+    pub fn parse(text: &str) -> Result<Self, DirectiveError> {
+        let mut res = Self::default();
+        for (name, value) in text.lines().filter_map(split_line) {
+            let directive = REGISTRY
+                .iter()
+                .find(|directive| directive.name == name)
+                .ok_or_else(|| DirectiveError::Unknown(name.to_owned()))?;
+            if let (Value::None, Some(_)) = (directive.value, value) {
+                return Err(DirectiveError::UnexpectedValue(directive.name));
+            }
+            (directive.apply)(&mut res, value)
+                .map_err(|msg| DirectiveError::InvalidValue(directive.name, msg))?;
+        }
+        Ok(res)
+    }
+}
+
+/// Reply to `;help`.
+pub fn help() -> String {
+    let mut res = String::from("Directives go on their own lines, next to the expressions:\n");
+    for directive in REGISTRY {
+        let value = match directive.value {
+            Value::None => String::new(),
+            Value::Optional(placeholder) => format!("[={placeholder}]"),
+        };
+        res.push_str(&format!(
+            ";{}{value} — {}\n",
+            directive.name, directive.help
+        ));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines() {
+        for (line, split) in [
+            (";full", Some(("full", None))),
+            (";diff=lines ", Some(("diff", Some("lines")))),
+            (";code=", Some(("code", Some("")))),
+            (";)", None),
+            ("; a comment", None),
+            (";full mode", None),
+            ("s/a/b/", None),
+        ] {
+            assert_eq!(split_line(line), split, "{line:?}");
+        }
+    }
+
+    #[test]
+    fn accepted() {
+        let directives = Directives::parse("s/a/b/\n;words\n;diff=lines\n;del\n;media").unwrap();
+        assert_eq!(directives.mode, Mode::Words);
+        assert_eq!(directives.diff, DiffMode::Always(Granularity::Lines));
+        assert!(directives.delete && directives.media && !directives.help);
+
+        let directives = Directives::parse(";code=rust\n;full\n;full").unwrap();
+        assert_eq!(directives.format, Format::Code(Some("rust".to_owned())));
+        assert_eq!(directives.mode, Mode::Full);
+        assert_eq!(
+            Directives::parse(";code=").unwrap().format,
+            Format::Code(None)
+        );
+        assert_eq!(
+            Directives::parse(";diff").unwrap().diff,
+            DiffMode::Always(Granularity::Words)
+        );
+        assert_eq!(
+            Directives::parse(";md\n;diff=off").unwrap().diff,
+            DiffMode::Never
+        );
+        assert_eq!(
+            Directives::parse("; not a directive").unwrap().mode,
+            Mode::Line
+        );
+    }
+
+    #[test]
+    fn rejected() {
+        for (text, err) in [
+            (";nope", DirectiveError::Unknown("nope".to_owned())),
+            (";del=yes", DirectiveError::UnexpectedValue("del")),
+            (";full=1", DirectiveError::UnexpectedValue("full")),
+            (
+                ";diff=chars",
+                DirectiveError::InvalidValue(
+                    "diff",
+                    "expected words/lines/off, got \"chars\"".to_owned(),
+                ),
+            ),
+            (
+                ";full\n;para",
+                DirectiveError::InvalidValue(
+                    "para",
+                    "only one of ;full, ;para, ;words and ;chars can be used".to_owned(),
+                ),
+            ),
+            (
+                ";md\n;html",
+                DirectiveError::InvalidValue(
+                    "html",
+                    "only one of ;md, ;html and ;code can be used".to_owned(),
+                ),
+            ),
+            (
+                ";md\n;diff",
+                DirectiveError::InvalidValue(
+                    "diff",
+                    "can't be combined with ;md, ;html or ;code".to_owned(),
+                ),
+            ),
+            (
+                ";diff=words\n;code",
+                DirectiveError::InvalidValue("code", "can't be combined with ;diff".to_owned()),
+            ),
+        ] {
+            assert_eq!(Directives::parse(text).unwrap_err(), err, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn help_lists_every_directive() {
+        let help = help();
+        for directive in REGISTRY {
+            assert!(
+                help.contains(&format!(";{}", directive.name)),
+                "{}",
+                directive.name
+            );
+        }
+        assert!(help.contains(";diff[=words|lines|off]"));
+    }
+}
@@ -0,0 +1,127 @@
+//! Native replacements for `timeout(1)` and `prlimit(1)`.
+
+use std::{io, time::Duration};
+
+use serde::Deserialize;
+
+#[cfg(target_env = "gnu")]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(target_env = "gnu"))]
+type Resource = libc::c_int;
+
+/// Resource limits for a single perl run. RLIMIT values are passed to
+/// setrlimit(2) as is, cgroup ones only apply when cgroups are available.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// How long perl may run before it gets SIGTERM.
+    pub timeout_ms: u64,
+    /// How long perl has to exit after SIGTERM before it gets SIGKILL.
+    pub kill_after_ms: u64,
+    /// RLIMIT_RSS, bytes.
+    pub rss_limit: u64,
+    /// RLIMIT_CPU, seconds.
+    pub cpu_limit: u64,
+    /// RLIMIT_MEMLOCK, bytes.
+    pub memlock_limit: u64,
+    /// cgroup `memory.max`, bytes.
+    pub memory_max: u64,
+    /// cgroup `pids.max`, the sandbox's own processes included.
+    pub pids_max: u64,
+    /// cgroup `cpu.max`, percent of a single CPU.
+    pub cpu_max_percent: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 500,
+            kill_after_ms: 1000,
+            rss_limit: 4194304,
+            cpu_limit: 2,
+            memlock_limit: 65535,
+            memory_max: 64 << 20,
+            pids_max: 16,
+            cpu_max_percent: 50,
+        }
+    }
+}
+
+/// Per-mode overrides of [`Limits`], unset fields keep the shared value.
+#[derive(Debug, Default, Deserialize)]
+pub struct LimitOverrides {
+    timeout_ms: Option<u64>,
+    kill_after_ms: Option<u64>,
+    rss_limit: Option<u64>,
+    cpu_limit: Option<u64>,
+    memlock_limit: Option<u64>,
+    memory_max: Option<u64>,
+    pids_max: Option<u64>,
+    cpu_max_percent: Option<u64>,
+}
+
+impl Limits {
+    pub fn with(self, overrides: &LimitOverrides) -> Self {
+        Self {
+            timeout_ms: overrides.timeout_ms.unwrap_or(self.timeout_ms),
+            kill_after_ms: overrides.kill_after_ms.unwrap_or(self.kill_after_ms),
+            rss_limit: overrides.rss_limit.unwrap_or(self.rss_limit),
+            cpu_limit: overrides.cpu_limit.unwrap_or(self.cpu_limit),
+            memlock_limit: overrides.memlock_limit.unwrap_or(self.memlock_limit),
+            memory_max: overrides.memory_max.unwrap_or(self.memory_max),
+            pids_max: overrides.pids_max.unwrap_or(self.pids_max),
+            cpu_max_percent: overrides.cpu_max_percent.unwrap_or(self.cpu_max_percent),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn kill_after(&self) -> Duration {
+        Duration::from_millis(self.kill_after_ms)
+    }
+
+    /// RLIMITs a single run is put under by the worker that forks it. The
+    /// worker itself has to be able to fork, so these can't be applied to
+    /// the whole sandbox.
+    pub fn rlimits(&self) -> [(Resource, libc::rlim_t); 5] {
+        [
+            (libc::RLIMIT_MEMLOCK, self.memlock_limit),
+            (libc::RLIMIT_RSS, self.rss_limit),
+            (libc::RLIMIT_CPU, self.cpu_limit),
+            (libc::RLIMIT_FSIZE, 0),
+            (libc::RLIMIT_NPROC, 1),
+        ]
+    }
+}
+
+/// Moves the current process into its own process group, so that
+/// [`kill_group`] reaches everything the sandbox spawned, and forbids
+/// writing files. Meant to be called from a `pre_exec` hook, so it only does
+/// async-signal-safe things.
+pub fn pre_exec() -> io::Result<()> {
+    let fsize = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: plain syscalls with valid arguments.
+    unsafe {
+        if libc::setpgid(0, 0) != 0 || libc::setrlimit(libc::RLIMIT_FSIZE, &fsize) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Sends `signal` to every process in the group led by `pid`.
+pub fn kill_group(pid: u32, signal: libc::c_int) {
+    // SAFETY: kill(2) has no memory safety preconditions.
+    if unsafe { libc::kill(-(pid as libc::pid_t), signal) } != 0 {
+        let err = io::Error::last_os_error();
+        // The group might have exited on its own in the meantime.
+        if err.raw_os_error() != Some(libc::ESRCH) {
+            tracing::warn!(%err, pid, signal, "failed to signal process group");
+        }
+    }
+}
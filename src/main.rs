@@ -1,20 +1,40 @@
-use std::{fmt, path::PathBuf, process::Stdio, sync::Arc};
+mod cgroup;
+mod config;
+mod delivery;
+mod diff;
+mod directive;
+mod limits;
+mod native;
+mod perl;
+mod policy;
+mod pool;
+mod sandbox;
+mod selftest;
+mod settings;
+mod subst;
 
-use color_eyre::eyre::{self, ensure, eyre};
-use serde::Deserialize;
+use std::sync::Arc;
+
+use color_eyre::eyre::{self, bail, ensure};
 use teloxide::{
     dptree,
     prelude::{Dispatcher, Request, Requester},
-    types::{Message, MessageId, Update, UpdateKind},
-    ApiError, Bot, RequestError,
-};
-use tokio::{
-    io::{AsyncReadExt as _, AsyncWriteExt as _},
-    process::Command,
-    sync::Semaphore,
+    types::{Message, Update, UpdateKind},
+    Bot,
 };
 use tracing_subscriber::EnvFilter;
 
+use crate::{
+    cgroup::Cgroups,
+    config::Config,
+    delivery::{Delivery, Format, Reply},
+    diff::DiffMode,
+    directive::Directives,
+    perl::run_perl,
+    pool::Pool,
+    settings::SettingsStore,
+};
+
 macro_rules! or_ok {
     ($x:expr) => {{
         match $x {
@@ -24,121 +44,35 @@ macro_rules! or_ok {
     }};
 }
 
-#[derive(Deserialize)]
-#[serde(transparent)]
-struct Token(String);
-
-impl fmt::Debug for Token {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str("Token(hidden)")
-    }
-}
-
-#[derive(Debug, Deserialize)]
-struct Config {
-    token: Token,
-    db_path: PathBuf,
-    #[serde(default = "default_max_parallel")]
-    max_parallel: usize,
-    // set by Nix
-    bwrap: PathBuf,
-    perl: PathBuf,
-    prlimit: PathBuf,
-    timeout: PathBuf,
-    allow_dirs: Vec<PathBuf>,
-}
-
-fn default_max_parallel() -> usize {
-    16
-}
-
-async fn run_perl(
-    exprs: impl IntoIterator<Item = &str>,
-    input: &str,
-    cfg: &Config,
-    full: bool,
-) -> eyre::Result<String> {
-    let mut cmd = Command::new(&cfg.timeout);
-
-    #[rustfmt::skip]
-    cmd
-    .stdin(Stdio::piped())
-    .stdout(Stdio::piped())
-    .stderr(Stdio::null())
-    .env_clear()
-    .env("LANG", "C")
-    .args(["--signal", "TERM", "--kill-after", "1s", "0.5s"])
-    .arg(&cfg.prlimit).args(["--memlock=65535", "--rss=4194304", "--cpu=2"])
-    .arg(&cfg.bwrap).args(["--unshare-all", "--proc", "/proc", "--dev", "/dev"])
-                    .args(cfg.allow_dirs.iter().flat_map(|dir| ["--ro-bind".as_ref(), dir.as_os_str(), dir.as_os_str()]))
-    .arg(&cfg.prlimit).args(["--nproc=1", "--fsize=0"])
-    .arg(&cfg.perl).args(["-Mutf8", "-e", "BEGIN { binmode STDIN, ':encoding(UTF-8)'; binmode STDOUT, ':encoding(UTF-8)'; }"]);
-
-    if full {
-        cmd.args(["-e", "local $/; $_ = <>; @W = split;"]);
-    } else {
-        cmd.args(["-lne", "@W = split;"]);
-    }
-
-    for expr in exprs {
-        cmd.args(["-E", &format!("{};", expr)]);
-    }
-
-    cmd.args(["-e", "say"]);
-
-    let mut child = cmd.spawn()?;
-    let mut stdin = child.stdin.take().unwrap();
-    let mut stdout = child.stdout.take().unwrap();
-    stdin.write_all(input.as_bytes()).await?;
-    drop(stdin);
-
-    let mut buf = [0_u8; 4096];
-    let mut cur = buf.as_mut_slice();
-    while !cur.is_empty() {
-        let n = stdout.read(cur).await?;
-        if n == 0 {
-            break;
-        }
-
-        cur = &mut cur[n..];
-    }
-
-    let status = child.wait().await?;
-    ensure!(status.success(), "perl exited with code {:?}", status);
-
-    Ok(String::from_utf8_lossy(&buf).into())
-}
-
-fn filter_exprs(raw_exprs: &str) -> impl Iterator<Item = &str> {
-    raw_exprs
-        .lines()
-        .filter(|line| matches!(line.get(..2), Some("s/" | "s(" | "s[" | "s<" | "s{")))
-}
-
-fn unique_id(message: &Message) -> [u8; 12] {
-    let mut res = [0; 12];
-    res[..8].copy_from_slice(&message.chat.id.0.to_le_bytes());
-    res[8..].copy_from_slice(&message.id.0.to_le_bytes());
-    res
-}
-
 async fn do_main() -> eyre::Result<()> {
-    let cfg: Config = envy::from_env()?;
+    let cfg = Config::from_env()?;
     tracing::info!(
         config = format_args!("{cfg:?}"),
         "Starting perlsub Telegram bot"
     );
+    let sandbox = sandbox::from_config(&cfg).await?;
+    let cgroups = Cgroups::from_config(&cfg);
+    let pool = Arc::new(Pool::new(&cfg, sandbox, cgroups)?);
+    let report = selftest::run(&cfg, &pool).await?;
+    if !report.passed() {
+        bail!("sandbox selftest failed, refusing to start:\n{report}");
+    }
+    tracing::info!("sandbox selftest passed");
     let cfg = Arc::new(cfg);
     let db = sled::open(&cfg.db_path)?;
-    let semaphore = Arc::new(Semaphore::new(cfg.max_parallel));
+    let settings = SettingsStore::open(&db)?;
 
     let bot = Bot::new(&cfg.token.0);
+    let me = Arc::new(bot.get_me().await?);
+    let delivery = Delivery::new(bot.clone(), db);
     Dispatcher::builder(
         bot,
         dptree::endpoint(move |bot: Bot, update: Update| {
             let cfg = cfg.clone();
-            let db = db.clone();
-            let semaphore = semaphore.clone();
+            let pool = pool.clone();
+            let delivery = delivery.clone();
+            let settings = settings.clone();
+            let me = me.clone();
             async move {
                 let (message, edited) = match update.kind {
                     UpdateKind::Message(message) => (message, false),
@@ -146,47 +80,151 @@ async fn do_main() -> eyre::Result<()> {
                     _ => return Ok(()),
                 };
 
-                let reply_to = or_ok!(message.reply_to_message());
-                let text = or_ok!(reply_to.text());
+                if !edited {
+                    if let Some(reply) =
+                        settings::handle_command(&bot, me.username(), &settings, &message).await?
+                    {
+                        let mut request = bot.send_message(message.chat.id, reply);
+                        request.reply_to_message_id = Some(message.id);
+                        request.send().await?;
+                        return Ok(());
+                    }
+                }
+
                 let raw_exprs = or_ok!(message.text());
-                let mut exprs = filter_exprs(raw_exprs).peekable();
-                or_ok!(exprs.peek());
-                let full = raw_exprs.lines().any(|line| line == ";full");
-                let res = {
-                    let _permit = semaphore.acquire().await?;
-                    run_perl(exprs, text, &cfg, full).await?
-                };
-                if res.is_empty() {
+                let directives = Directives::parse(raw_exprs);
+                // Other bots may have a `;help` too.
+                let to_us = message.chat.is_private()
+                    || message
+                        .reply_to_message()
+                        .and_then(Message::from)
+                        .is_some_and(|user| user.id == me.id);
+                if !edited && to_us && matches!(directives, Ok(Directives { help: true, .. })) {
+                    let mut request = bot.send_message(message.chat.id, directive::help());
+                    request.reply_to_message_id = Some(message.id);
+                    request.send().await?;
                     return Ok(());
                 }
 
-                if edited {
-                    let original_reply_id_bytes = db
-                        .get(unique_id(&message))?
-                        .ok_or_else(|| eyre!("original message {} not found in db", message.id))?;
-                    let original_reply_id = MessageId(i32::from_le_bytes(
-                        (&*original_reply_id_bytes)
-                            .try_into()
-                            .map_err(|_| eyre!("wrong ID len in db"))?,
-                    ));
-
-                    if let Err(err) = bot
-                        .edit_message_text(message.chat.id, original_reply_id, res)
-                        .send()
-                        .await
-                    {
-                        if !matches!(err, RequestError::Api(ApiError::MessageNotModified)) {
-                            return Err(err.into());
-                        }
+                let reply_to = or_ok!(message.reply_to_message());
+                // Media are run on their caption.
+                let (text, entities, caption) = match reply_to.text() {
+                    Some(text) => (text, reply_to.entities(), false),
+                    None => (
+                        or_ok!(reply_to.caption()),
+                        reply_to.caption_entities(),
+                        true,
+                    ),
+                };
+                let entities = entities.unwrap_or_default();
+                let exprs = subst::parse_lines(raw_exprs);
+                for (line, err) in &exprs.rejected {
+                    tracing::debug!(chat = %message.chat.id, line, %err, "rejected expression");
+                }
+                if exprs.accepted.is_empty() && exprs.rejected.is_empty() {
+                    return Ok(());
+                }
+                let chat_settings = settings.get(message.chat.id)?;
+                let (directives, directive_err) = match directives {
+                    Ok(directives) => (directives, None),
+                    Err(err) => (Directives::default(), Some(err)),
+                };
+                let mode = directives.mode;
+                let policy = cfg.policy().intersect(chat_settings.policy);
+                let violation = exprs
+                    .accepted
+                    .iter()
+                    .find_map(|subst| policy.check(subst).err());
+                // Only results go into captions, not errors.
+                let mut media = false;
+                let reply = if let Some(violation) = violation {
+                    tracing::debug!(chat = %message.chat.id, %violation, "expression refused");
+                    Reply::plain(violation.to_string())
+                } else if exprs.accepted.is_empty() {
+                    // Only complain about a broken expression if there's
+                    // nothing else to run.
+                    let (_, err) = or_ok!(exprs
+                        .rejected
+                        .iter()
+                        .find(|(line, err)| err.worth_reporting(line)));
+                    if !chat_settings.report_errors {
+                        return Ok(());
                     }
+                    Reply::plain(format!("syntax error: {err}"))
+                } else if let Some(err) = directive_err {
+                    tracing::debug!(chat = %message.chat.id, %err, "bad directive");
+                    if !chat_settings.report_errors {
+                        return Ok(());
+                    }
+                    Reply::plain(err.to_string())
                 } else {
-                    let mut request = bot.send_message(reply_to.chat.id, res);
-                    request.reply_to_message_id = Some(reply_to.id);
-                    let sent = request.send().await?;
-                    db.insert(unique_id(&message), &sent.id.0.to_le_bytes())?;
+                    let res = match native::run(&exprs.accepted, text, &cfg, mode) {
+                        Some(out) => Ok(out),
+                        None => {
+                            let sources = exprs
+                                .accepted
+                                .iter()
+                                .map(|expr| perl::statement(expr, mode));
+                            let opcodes = cfg.opcodes.as_deref();
+                            run_perl(sources, text, &cfg, &pool, mode, opcodes).await?
+                        }
+                    };
+                    media = directives.media && caption && res.is_ok();
+                    match res {
+                        Ok(out) if directives.format != Format::Plain => {
+                            let mut reply = directives.format.apply(out.text);
+                            if out.truncated {
+                                reply.note("[output truncated]");
+                            }
+                            reply
+                        }
+                        // What's missing would show up as deleted in a diff.
+                        Ok(out) if out.truncated => {
+                            let mut reply = diff::reply(
+                                text,
+                                entities,
+                                out.text,
+                                DiffMode::Never,
+                                cfg.auto_diff_chars,
+                            );
+                            reply.note(match directives.diff {
+                                DiffMode::Always(_) => "[output truncated, so no diff]",
+                                DiffMode::Auto | DiffMode::Never => "[output truncated]",
+                            });
+                            reply
+                        }
+                        Ok(out) => diff::reply(
+                            text,
+                            entities,
+                            out.text,
+                            directives.diff,
+                            cfg.auto_diff_chars,
+                        ),
+                        Err(err) => {
+                            tracing::debug!(chat = %message.chat.id, %err, "perl failed");
+                            if !chat_settings.report_errors {
+                                return Ok(());
+                            }
+                            Reply::plain(err.to_string())
+                        }
+                    }
+                };
+                if reply.text.trim().is_empty() {
+                    return Ok(());
                 }
 
-                if raw_exprs.lines().any(|line| line == ";del") {
+                delivery
+                    .deliver(
+                        &message,
+                        reply_to,
+                        &reply,
+                        edited,
+                        chat_settings.delivery,
+                        media,
+                    )
+                    .await?;
+
+                if directives.delete {
                     bot.delete_message(message.chat.id, message.id)
                         .send()
                         .await?;
@@ -204,11 +242,27 @@ async fn do_main() -> eyre::Result<()> {
     Ok(())
 }
 
+/// `perlsub selftest`: runs the sandbox canaries and exits.
+async fn selftest_main() -> eyre::Result<()> {
+    let cfg = Config::from_env()?;
+    let sandbox = sandbox::from_config(&cfg).await?;
+    let cgroups = Cgroups::from_config(&cfg);
+    let pool = Pool::new(&cfg, sandbox, cgroups)?;
+    let report = selftest::run(&cfg, &pool).await?;
+    print!("{report}");
+    ensure!(report.passed(), "sandbox selftest failed");
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     color_eyre::install()?;
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .init();
-    do_main().await
+    match std::env::args().nth(1).as_deref() {
+        None => do_main().await,
+        Some("selftest") => selftest_main().await,
+        Some(other) => bail!("unknown subcommand {other:?}, expected `selftest`"),
+    }
 }
@@ -0,0 +1,473 @@
+//! In-process fast path for plain substitutions.
+//!
+//! Only a subset of perl's regex and replacement syntax is translated, the
+//! one where the `regex` crate is known to behave like perl. Anything else,
+//! and any run that goes over its time budget, is left to perl.
+
+use std::time::{Duration, Instant};
+
+use regex::{Regex, RegexBuilder};
+
+use crate::{
+    config::Config,
+    perl::{Mode, PerlOutput},
+    subst::{Delimiter, Operator, Substitution},
+};
+
+/// Modifiers that can be translated. `o` is a no-op for patterns without
+/// variables.
+const NATIVE_MODIFIERS: &str = "gimso";
+
+/// Escapes that mean the same in both engines. `\b{...}`, `\<` and `\>` are
+/// assertions in the `regex` crate, `\v` is a single character rather than
+/// a class.
+const PATTERN_ESCAPES: &str = "dDwWsSbBAzntrfaxpP";
+
+/// Characters with a multi-character case folding, e.g. `ß` matches `ss`
+/// case-insensitively in perl but not in the `regex` crate.
+fn has_multi_char_fold(c: char) -> bool {
+    matches!(
+        c,
+        '\u{DF}'
+            | '\u{130}'
+            | '\u{149}'
+            | '\u{1F0}'
+            | '\u{390}'
+            | '\u{3B0}'
+            | '\u{587}'
+            | '\u{1E96}'..='\u{1E9E}'
+            | '\u{1F50}'..='\u{1FFC}'
+            | '\u{FB00}'..='\u{FB17}'
+    )
+}
+
+/// Whether an inline flag group like `(?i)` or `(?x-i:...)` mentions `i`.
+/// Turning it off counts too, it may be on in a surrounding group.
+fn inline_ignore_case(pattern: &str) -> bool {
+    pattern.match_indices("(?").any(|(idx, _)| {
+        pattern[idx + 2..]
+            .chars()
+            .take_while(|&c| c.is_ascii_alphabetic() || c == '-')
+            .any(|c| c == 'i')
+    })
+}
+
+/// Piece of a replacement string.
+#[derive(Debug)]
+enum Piece {
+    Literal(String),
+    /// `$1`, `${1}` or `$&` for 0.
+    Group(usize),
+}
+
+/// Checks that `pattern` only uses syntax both engines agree on.
+fn translatable_pattern(pattern: &str, delimiter: Delimiter) -> bool {
+    if pattern.is_empty() {
+        // Means "the last successful pattern" to perl.
+        return false;
+    }
+
+    let mut in_class = false;
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                // Perl drops the backslash in front of some delimiters but
+                // not others, don't bother.
+                Some(c) if delimiter != Delimiter::Single('/') && delimiter.contains(c) => {
+                    return false
+                }
+                Some('<' | '>') => return false,
+                Some('b' | 'B') if chars.peek() == Some(&'{') => return false,
+                Some(c) if c.is_ascii_punctuation() || PATTERN_ESCAPES.contains(c) => {}
+                _ => return false,
+            },
+            // Interpolation.
+            '$' if !matches!(chars.peek(), None | Some(')' | '|')) || in_class => return false,
+            '@' => return false,
+            '[' if in_class => return false,
+            '[' => {
+                in_class = true;
+                // A leading `]` (after an optional `^`) is a literal.
+                if chars.peek() == Some(&'^') {
+                    chars.next();
+                }
+                if chars.peek() == Some(&']') {
+                    chars.next();
+                }
+            }
+            ']' if in_class => in_class = false,
+            // Set operations in the `regex` crate.
+            '&' | '-' | '~' if in_class && chars.peek() == Some(&c) => return false,
+            // Only groups and the flags that mean the same.
+            '(' if !in_class && chars.peek() == Some(&'?') => {
+                chars.next();
+                match chars.peek() {
+                    Some(':') => {}
+                    Some('<') | Some('P') => {
+                        chars.next();
+                        if chars.peek().is_some_and(|c| matches!(c, '=' | '!')) {
+                            // Lookbehind.
+                            return false;
+                        }
+                    }
+                    _ => {
+                        for c in chars.by_ref() {
+                            match c {
+                                'i' | 'm' | 's' | '-' => {}
+                                ':' | ')' => break,
+                                _ => return false,
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    !in_class
+}
+
+/// Translates a perl replacement string, `None` if it does more than
+/// referring to groups.
+fn translate_replacement(replacement: &str) -> Option<Vec<Piece>> {
+    let mut pieces = Vec::new();
+    let mut literal = String::new();
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                'n' => literal.push('\n'),
+                't' => literal.push('\t'),
+                'r' => literal.push('\r'),
+                c if c.is_ascii_punctuation() => literal.push(c),
+                _ => return None,
+            },
+            '$' => {
+                let group = match chars.next()? {
+                    '&' => 0,
+                    '{' => {
+                        let mut digits = String::new();
+                        for c in chars.by_ref() {
+                            if c == '}' {
+                                break;
+                            }
+                            digits.push(c);
+                        }
+                        digits.parse().ok().filter(|&group| group > 0)?
+                    }
+                    c @ '1'..='9' => {
+                        let mut digits = String::from(c);
+                        while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                            digits.push(c);
+                            chars.next();
+                        }
+                        digits.parse().ok()?
+                    }
+                    _ => return None,
+                };
+                // `$1[0]`, `$1{x}` and `$1->[0]` are element accesses.
+                if matches!(chars.peek(), Some('[' | '{')) || chars.clone().take(2).eq(['-', '>']) {
+                    return None;
+                }
+                if !literal.is_empty() {
+                    pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                }
+                pieces.push(Piece::Group(group));
+            }
+            '@' if chars.peek().is_some_and(|c| !c.is_whitespace()) => return None,
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        pieces.push(Piece::Literal(literal));
+    }
+    Some(pieces)
+}
+
+/// A substitution compiled for the `regex` crate.
+#[derive(Debug)]
+struct NativeSubst {
+    regex: Regex,
+    replacement: Vec<Piece>,
+    global: bool,
+    ignore_case: bool,
+    /// Uses `^` or `$`, which perl treats differently around a trailing
+    /// newline.
+    anchored: bool,
+}
+
+/// Why a substitution couldn't be applied natively.
+enum Unsupported {
+    /// Perl would do something we can't.
+    Semantics,
+    /// Ran out of time.
+    Budget,
+}
+
+impl NativeSubst {
+    fn new(subst: &Substitution<'_>) -> Option<Self> {
+        let modifiers = &subst.modifiers;
+        let ignore_case = modifiers.ignore_case || inline_ignore_case(subst.pattern);
+        if subst.operator != Operator::Substitute
+            || subst.address.is_some()
+            || !modifiers.only(NATIVE_MODIFIERS)
+            || !subst.tail.is_empty()
+            || matches!(subst.pattern_delimiter, Delimiter::Single('\'' | '?'))
+            || matches!(subst.replacement_delimiter, Delimiter::Single('\''))
+            || !translatable_pattern(subst.pattern, subst.pattern_delimiter)
+            || (ignore_case && !subst.pattern.is_ascii())
+        {
+            return None;
+        }
+
+        let replacement = translate_replacement(subst.replacement)?;
+        let regex = RegexBuilder::new(subst.pattern)
+            .case_insensitive(modifiers.ignore_case)
+            .multi_line(modifiers.multi_line)
+            .dot_matches_new_line(modifiers.single_line)
+            .size_limit(1 << 20)
+            .dfa_size_limit(1 << 20)
+            .build()
+            .ok()?;
+
+        if modifiers.global {
+            // Perl allows an empty match right after a non-empty one, the
+            // `regex` crate doesn't.
+            let hir = regex_syntax::ParserBuilder::new()
+                .case_insensitive(modifiers.ignore_case)
+                .multi_line(modifiers.multi_line)
+                .dot_matches_new_line(modifiers.single_line)
+                .build()
+                .parse(subst.pattern)
+                .ok()?;
+            if hir.properties().minimum_len() == Some(0) {
+                return None;
+            }
+        }
+
+        Some(Self {
+            regex,
+            replacement,
+            global: modifiers.global,
+            ignore_case,
+            anchored: subst.pattern.contains(['^', '$']),
+        })
+    }
+
+    fn apply(&self, text: &str, deadline: Instant) -> Result<String, Unsupported> {
+        if (self.anchored && text.ends_with('\n'))
+            || (self.ignore_case && text.chars().any(has_multi_char_fold))
+        {
+            return Err(Unsupported::Semantics);
+        }
+
+        let mut res = String::with_capacity(text.len());
+        let mut last = 0;
+        for caps in self.regex.captures_iter(text) {
+            if Instant::now() > deadline {
+                return Err(Unsupported::Budget);
+            }
+
+            let whole = caps.get(0).unwrap();
+            res.push_str(&text[last..whole.start()]);
+            for piece in &self.replacement {
+                match piece {
+                    Piece::Literal(literal) => res.push_str(literal),
+                    Piece::Group(group) => {
+                        res.push_str(caps.get(*group).map_or("", |group| group.as_str()))
+                    }
+                }
+            }
+            last = whole.end();
+            if !self.global {
+                break;
+            }
+        }
+        res.push_str(&text[last..]);
+        Ok(res)
+    }
+}
+
+/// Runs `exprs` on `input` without perl, the same way `run_perl` would.
+/// `None` if any of them needs perl, or if `mode` isn't line by line or
+/// the whole text.
+pub fn run(
+    exprs: &[Substitution<'_>],
+    input: &str,
+    cfg: &Config,
+    mode: Mode,
+) -> Option<PerlOutput> {
+    if !cfg.native_subst || !matches!(mode, Mode::Line | Mode::Full) {
+        return None;
+    }
+
+    let substs = exprs
+        .iter()
+        .map(NativeSubst::new)
+        .collect::<Option<Vec<_>>>()?;
+
+    let deadline = Instant::now() + Duration::from_millis(cfg.native_budget_ms);
+    let apply = |text: &str| {
+        substs
+            .iter()
+            .try_fold(text.to_owned(), |text, subst| subst.apply(&text, deadline))
+    };
+    let res = if mode == Mode::Full {
+        apply(input).map(|text| text + "\n")
+    } else {
+        // `perl -lne` splits on newlines and chomps them, `say` puts them
+        // back, even on the last line.
+        input
+            .split_inclusive('\n')
+            .try_fold(String::new(), |res, line| {
+                let line = line.strip_suffix('\n').unwrap_or(line);
+                Ok(res + &apply(line)? + "\n")
+            })
+    };
+
+    let mut text = match res {
+        Ok(text) => text,
+        Err(Unsupported::Semantics) => return None,
+        Err(Unsupported::Budget) => {
+            tracing::debug!("native substitution ran out of time, falling back to perl");
+            return None;
+        }
+    };
+    let truncated = text.len() > cfg.max_output_bytes;
+    if truncated {
+        let mut end = cfg.max_output_bytes;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+    Some(PerlOutput { text, truncated })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subst;
+
+    /// Result of `expr` on `text` in-process, `None` if it's left to perl.
+    fn native(expr: &str, text: &str) -> Option<String> {
+        let subst = NativeSubst::new(&subst::parse(expr).unwrap())?;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        subst.apply(text, deadline).ok()
+    }
+
+    #[test]
+    fn translatable_patterns() {
+        let slash = Delimiter::Single('/');
+        for pattern in [
+            "a+b",
+            r"\d{2}\.\w",
+            "[a-z]",
+            "[^]a]",
+            "(?:x)|(y)",
+            "(?<name>x)",
+            "(?i)a(?-i:b)",
+            "a$",
+            "(a|$)",
+            r"\/",
+        ] {
+            assert!(translatable_pattern(pattern, slash), "{pattern:?}");
+        }
+        for pattern in [
+            "",
+            r"\<",
+            r"\b{wb}",
+            r"\1",
+            r"\v",
+            "$x",
+            "@x",
+            "[[:alpha:]]",
+            "[a&&b]",
+            "[a--b]",
+            "[a",
+            "(?<=a)b",
+            "(?<!a)b",
+            "(?x)a b",
+            "(?u)a",
+        ] {
+            assert!(!translatable_pattern(pattern, slash), "{pattern:?}");
+        }
+        assert!(!translatable_pattern(r"\}", Delimiter::Paired('{', '}')));
+    }
+
+    #[test]
+    fn replacements() {
+        for (replacement, pieces) in [
+            ("", "[]"),
+            ("x$1y", r#"[Literal("x"), Group(1), Literal("y")]"#),
+            ("${12}0", r#"[Group(12), Literal("0")]"#),
+            ("<$&>", r#"[Literal("<"), Group(0), Literal(">")]"#),
+            (r"a\n\$1", r#"[Literal("a\n$1")]"#),
+            ("a @ b", r#"[Literal("a @ b")]"#),
+        ] {
+            let translated = translate_replacement(replacement);
+            assert_eq!(
+                format!("{:?}", translated.unwrap()),
+                pieces,
+                "{replacement:?}"
+            );
+        }
+        for replacement in [
+            "$x", "${0}", "$1[0]", "$1{a}", "$1->[0]", "@x", r"\u$1", r"\1", "$",
+        ] {
+            assert!(
+                translate_replacement(replacement).is_none(),
+                "{replacement:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn inline_flags() {
+        for pattern in ["(?i)a", "(?-i:a)", "(?si)a", "x(?i:y)"] {
+            assert!(inline_ignore_case(pattern), "{pattern:?}");
+        }
+        for pattern in ["i", "(?:i)", "(?s)i", "(?m)a|i"] {
+            assert!(!inline_ignore_case(pattern), "{pattern:?}");
+        }
+    }
+
+    #[test]
+    fn case_folding() {
+        assert_eq!(native("s/a/b/ig", "AbA").as_deref(), Some("bbb"));
+        assert_eq!(
+            native("s/(?i)ab(?-i)c/x/g", "ABc ABC").as_deref(),
+            Some("x ABC")
+        );
+        // `ß` folds to `ss` in perl.
+        assert_eq!(native("s/ss/x/i", "Straße"), None);
+        assert_eq!(native("s/(?i)ss/x/", "Straße"), None);
+        assert_eq!(native("s/(?i)ss/x/", "Strasse").as_deref(), Some("Straxe"));
+        assert_eq!(native("s/é/e/i", "É"), None);
+        assert_eq!(native("s/(?i:é)/e/", "É"), None);
+        assert_eq!(native("s/é/e/", "é").as_deref(), Some("e"));
+    }
+
+    #[test]
+    fn left_to_perl() {
+        for (expr, text) in [
+            ("s/a/b/e", "a"),
+            ("s/a/b/x", "a"),
+            ("2s/a/b/", "a"),
+            ("s/a/b/; print", "a"),
+            ("y/a/b/", "a"),
+            ("m/a/", "a"),
+            ("s'a'b'", "a"),
+            ("s/x*/-/g", "abc"),
+            ("s/^a/b/", "a\n"),
+        ] {
+            assert_eq!(native(expr, text), None, "{expr:?}");
+        }
+        assert_eq!(native("s/^a/b/", "a").as_deref(), Some("b"));
+        assert_eq!(
+            native("s/(\\w+) (\\w+)/$2 $1/", "hello world").as_deref(),
+            Some("world hello")
+        );
+    }
+}
@@ -0,0 +1,393 @@
+use std::{
+    borrow::Cow, fmt, os::unix::process::ExitStatusExt as _, process::ExitStatus, time::Duration,
+};
+
+use color_eyre::eyre;
+
+use crate::{
+    config::Config,
+    limits::Limits,
+    pool::{Exit, Job, Pool},
+    subst::{Address, Line, Operator, Substitution},
+};
+
+/// Fragments of perl diagnostics that are only emitted at compile time.
+const COMPILE_ERROR_MARKERS: &[&str] = &[
+    "syntax error",
+    "aborted due to compilation errors",
+    "in regex; marked by",
+    "not terminated",
+    "Can't find string terminator",
+];
+
+/// How far below RLIMIT_CPU the CPU time the pool reports for a run killed
+/// by it can be. It is counted in clock ticks, and not quite the way the
+/// kernel checks the limit.
+const CPU_TIME_SLACK: Duration = Duration::from_millis(50);
+
+/// How the input is split into the records the expressions run on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Each line, like `perl -lne`.
+    #[default]
+    Line,
+    /// The whole text at once.
+    Full,
+    /// Each paragraph, like `perl -00 -lne`.
+    Paragraph,
+    /// Each whitespace-separated word, keeping the whitespace between them.
+    Words,
+    /// Each grapheme cluster.
+    Chars,
+}
+
+/// Reason a perl run did not produce output.
+#[derive(Debug)]
+pub enum PerlError {
+    /// The expressions failed to compile.
+    Syntax(String),
+    /// Perl died while running the expressions.
+    Runtime(String),
+    /// Wall-clock limit was hit.
+    Timeout,
+    /// Memory limit was hit.
+    MemoryLimit,
+    /// CPU time limit was hit.
+    CpuLimit,
+    /// The seccomp filter killed perl.
+    SyscallDenied,
+    /// The Safe compartment refused to compile an operation.
+    Trapped(String),
+    /// Anything we couldn't classify.
+    Other(ExitStatus),
+}
+
+impl fmt::Display for PerlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax(msg) => write!(f, "syntax error: {msg}"),
+            Self::Runtime(msg) => write!(f, "runtime error: {msg}"),
+            Self::Timeout => f.write_str("timed out"),
+            Self::MemoryLimit => f.write_str("memory limit exceeded"),
+            Self::CpuLimit => f.write_str("CPU time limit exceeded"),
+            Self::SyscallDenied => f.write_str("forbidden system call"),
+            Self::Trapped(op) => write!(f, "forbidden operation: {op}"),
+            Self::Other(status) => write!(f, "perl failed ({status})"),
+        }
+    }
+}
+
+impl PerlError {
+    fn classify(
+        status: ExitStatus,
+        cpu_time: Duration,
+        stderr: &str,
+        cfg: &Config,
+        limits: &Limits,
+    ) -> Self {
+        // The sandbox either re-raises the child's signal or exits with
+        // 128 + signo, so check for both.
+        let signal = status.signal().or_else(|| {
+            status
+                .code()
+                .filter(|&code| code > 128)
+                .map(|code| code - 128)
+        });
+        // With equal soft and hard RLIMIT_CPU the kernel sends SIGKILL right
+        // away instead of SIGXCPU, but so does everything else that kills a
+        // run.
+        let cpu_limit = Duration::from_secs(limits.cpu_limit);
+        match signal {
+            Some(libc::SIGXCPU) => return Self::CpuLimit,
+            Some(libc::SIGKILL) if cpu_time + CPU_TIME_SLACK >= cpu_limit => return Self::CpuLimit,
+            _ => {}
+        }
+        if signal == Some(libc::SIGSYS) {
+            return Self::SyscallDenied;
+        }
+
+        if stderr.contains("Out of memory") {
+            return Self::MemoryLimit;
+        }
+
+        if let Some(op) = trapped_op(stderr) {
+            return Self::Trapped(op.to_owned());
+        }
+
+        let msg = sanitize_stderr(stderr, cfg);
+        if COMPILE_ERROR_MARKERS
+            .iter()
+            .any(|marker| stderr.contains(marker))
+        {
+            Self::Syntax(msg)
+        } else if !msg.is_empty() {
+            Self::Runtime(msg)
+        } else {
+            Self::Other(status)
+        }
+    }
+}
+
+/// The operation in Safe's "'open' trapped by operation mask" error.
+fn trapped_op(stderr: &str) -> Option<&str> {
+    let (before, _) = stderr.split_once("' trapped by operation mask")?;
+    let start = before.rfind('\'')?;
+    Some(&before[start + 1..])
+}
+
+/// Makes perl's stderr presentable in a chat: drops the noise lines, hides
+/// sandbox paths and keeps only the first few lines.
+fn sanitize_stderr(stderr: &str, cfg: &Config) -> String {
+    const MAX_LINES: usize = 3;
+    const MAX_CHARS: usize = 300;
+
+    let mut res = stderr
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with("Execution of -e aborted"))
+        .take(MAX_LINES)
+        .collect::<Vec<_>>()
+        .join("\n");
+    for dir in &cfg.allow_dirs {
+        if let Some(dir) = dir.to_str() {
+            res = res.replace(dir, "…");
+        }
+    }
+
+    if let Some((idx, _)) = res.char_indices().nth(MAX_CHARS) {
+        res.truncate(idx);
+        res.push('…');
+    }
+    res
+}
+
+/// What perl printed.
+#[derive(Debug)]
+pub struct PerlOutput {
+    pub text: String,
+    /// Perl printed more than `max_output_bytes` and the rest was dropped.
+    pub truncated: bool,
+}
+
+/// Drops an incomplete UTF-8 sequence left at the end of `bytes` by
+/// truncation.
+fn trim_partial_char(bytes: &mut Vec<u8>) {
+    let tail_start = bytes.len().saturating_sub(3);
+    for idx in (tail_start..bytes.len()).rev() {
+        let byte = bytes[idx];
+        if byte & 0b1100_0000 == 0b1000_0000 {
+            // Continuation byte, keep looking for the start of the sequence.
+            continue;
+        }
+
+        let len = match byte {
+            0xF0.. => 4,
+            0xE0.. => 3,
+            0xC0.. => 2,
+            _ => 1,
+        };
+        if bytes.len() - idx < len {
+            bytes.truncate(idx);
+        }
+        break;
+    }
+}
+
+/// Perl statement for a parsed expression. Substitutions and
+/// transliterations run as written. In line mode a match drops the lines it
+/// doesn't match and replaces the others with its captures, if it has any.
+/// In `;full` mode it replaces the text with all of its matches, one per
+/// line. The other modes treat their records like lines.
+pub fn statement<'a>(expr: &Substitution<'a>, mode: Mode) -> Cow<'a, str> {
+    if expr.operator != Operator::Match && expr.address.is_none() {
+        return Cow::Borrowed(expr.source);
+    }
+
+    let mut code = if expr.operator != Operator::Match {
+        expr.body.to_owned()
+    } else if mode == Mode::Full {
+        let global = if expr.modifiers.global { "" } else { "g" };
+        // With /g in list context perl returns the captures of all matches
+        // in a row, `$#+` per match. `@{^CAPTURE}` would be simpler, but it
+        // loads a module, which the compartment doesn't allow.
+        format!(
+            "{{ my @m = {}{global}; my $n = @m ? $#+ : 0; \
+             $_ = join \"\\n\", $n ? (map {{ join ' ', map {{ $_ // '' }} @m[$_ * $n .. $_ * $n + $n - 1] }} 0 .. @m / $n - 1) : @m; }}",
+            expr.body,
+        )
+    } else {
+        format!(
+            "{{ (my @m = {}) or next LINE; $_ = join ' ', map {{ $_ // '' }} @m if $#+; }}",
+            expr.body,
+        )
+    };
+    if !expr.tail.is_empty() {
+        code.push_str("; ");
+        code.push_str(expr.tail);
+    }
+    if let Some(address) = expr.address {
+        code = format!("if ({}) {{ {code} }}", condition(address, mode));
+    }
+    Cow::Owned(code)
+}
+
+/// Perl condition for a single line of an address. `;words` and `;chars`
+/// have read all of the input before their first record, so they keep track
+/// of the last one themselves.
+fn line_condition(line: Line<'_>, mode: Mode) -> String {
+    match line {
+        Line::Number(number) => format!("$. == {number}"),
+        Line::Last if matches!(mode, Mode::Words | Mode::Chars) => "$eof".to_owned(),
+        Line::Last => "eof".to_owned(),
+        Line::Matching(regex) => format!("/{regex}/"),
+    }
+}
+
+/// Perl condition for an address. Ranges use the flip-flop operator, whose
+/// three-dot form doesn't check the end on the line that matched the start,
+/// just like sed. An end line number is checked on that line too, since sed
+/// stops right away at one that has already passed.
+fn condition(address: Address<'_>, mode: Mode) -> String {
+    match address {
+        Address::Line(line) => line_condition(line, mode),
+        Address::Range(start, Line::Number(last)) => {
+            format!("({}) .. ($. >= {last})", line_condition(start, mode))
+        }
+        Address::Range(start, end) => {
+            format!(
+                "({}) ... ({})",
+                line_condition(start, mode),
+                line_condition(end, mode)
+            )
+        }
+    }
+}
+
+/// Program a job evaluates, the equivalent of the `perl -lne` (or slurping
+/// `perl -e`) command line the expressions used to be passed on. `#line`
+/// makes perl report positions in the expressions like it did then. The
+/// worker enables `utf8` and the current feature bundle.
+///
+/// `@W` holds the words of the record in the modes where records have more
+/// than one. Dropping a record with `next LINE` works in all of them but
+/// `;full`. `;words` and `;chars` number their records in `$.` and set
+/// `$eof` on the last one for addresses.
+fn program(exprs: impl IntoIterator<Item = impl AsRef<str>>, mode: Mode) -> String {
+    let mut code = String::from(match mode {
+        Mode::Line => "$\\ = \"\\n\";\nLINE: while (<STDIN>) { chomp; @W = split;\n",
+        Mode::Full => "local $/; $_ = <STDIN>; @W = split;\n",
+        Mode::Paragraph => {
+            "$/ = \"\"; $\\ = \"\\n\"; my $paras = 0;\n\
+             LINE: while (<STDIN>) { chomp; @W = split;\n"
+        }
+        // A leading separator splits off an empty first word, which is
+        // passed through like the separators.
+        Mode::Words => {
+            "my @words = split /(\\s+)/, do { local $/; <STDIN> // \"\" };\n\
+             my ($out, $n) = (\"\", 0);\n\
+             LINE: while (@words) { ($_, my $sep) = splice @words, 0, 2; $sep //= \"\";\n\
+             if ($_ eq \"\") { $out .= $sep; next LINE }\n\
+             $. = ++$n; my $eof = !@words;\n"
+        }
+        Mode::Chars => {
+            "my @chars = do { local $/; <STDIN> // \"\" } =~ /\\X/g;\n\
+             my ($out, $n) = (\"\", 0);\n\
+             LINE: while (@chars) { $_ = shift @chars; $. = ++$n; my $eof = !@chars;\n"
+        }
+    });
+    code.push_str("#line 1 \"-e\"\n");
+
+    for expr in exprs {
+        code.push_str(expr.as_ref());
+        code.push_str(";\n");
+    }
+
+    code.push_str(match mode {
+        Mode::Line => "say;\n}\n",
+        Mode::Full => "say;\n",
+        Mode::Paragraph => "say \"\" if $paras++;\nsay;\n}\n",
+        Mode::Words => "$out .= $_ . $sep;\n}\nsay $out;\n",
+        Mode::Chars => "$out .= $_;\n}\nsay $out;\n",
+    });
+    code
+}
+
+/// Runs `exprs` on `input`, in a Safe compartment that permits `opcodes`
+/// if there are any.
+pub async fn run_perl(
+    exprs: impl IntoIterator<Item = impl AsRef<str>>,
+    input: &str,
+    cfg: &Config,
+    pool: &Pool,
+    mode: Mode,
+    opcodes: Option<&[String]>,
+) -> eyre::Result<Result<PerlOutput, PerlError>> {
+    let limits = cfg.limits(mode);
+    let job = Job {
+        code: &program(exprs, mode),
+        input,
+        limits,
+        max_output_bytes: cfg.max_output_bytes,
+        opcodes,
+    };
+    let (status, mut stdout, truncated, stderr, oom_killed, cpu_time) = match pool.run(job).await? {
+        Exit::Exited {
+            status,
+            stdout,
+            truncated,
+            stderr,
+            oom_killed,
+            cpu_time,
+        } => (status, stdout, truncated, stderr, oom_killed, cpu_time),
+        Exit::TimedOut => return Ok(Err(PerlError::Timeout)),
+    };
+
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        tracing::debug!(%status, %stderr, "perl failed");
+        if oom_killed {
+            return Ok(Err(PerlError::MemoryLimit));
+        }
+
+        let err = PerlError::classify(status, cpu_time, &stderr, cfg, limits);
+        if let PerlError::SyscallDenied = err {
+            tracing::warn!("perl was killed by the seccomp filter");
+        }
+        return Ok(Err(err));
+    }
+
+    if truncated {
+        trim_partial_char(&mut stdout);
+    }
+    let text = match String::from_utf8(stdout) {
+        Ok(text) => text,
+        Err(err) => String::from_utf8_lossy(err.as_bytes()).into_owned(),
+    };
+    Ok(Ok(PerlOutput { text, truncated }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subst;
+
+    fn address(expr: &str) -> Address<'_> {
+        subst::parse(expr).unwrap().address.unwrap()
+    }
+
+    #[test]
+    fn conditions() {
+        for (expr, mode, expected) in [
+            ("3s/a/b/", Mode::Line, "$. == 3"),
+            ("$s/a/b/", Mode::Line, "eof"),
+            ("$s/a/b/", Mode::Words, "$eof"),
+            ("/x/s/a/b/", Mode::Line, "/x/"),
+            ("2,4s/a/b/", Mode::Line, "($. == 2) .. ($. >= 4)"),
+            ("5,3s/a/b/", Mode::Line, "($. == 5) .. ($. >= 3)"),
+            ("/foo/,1s/a/b/", Mode::Line, "(/foo/) .. ($. >= 1)"),
+            ("2,/x/s/a/b/", Mode::Line, "($. == 2) ... (/x/)"),
+            ("/x/,$s/a/b/", Mode::Chars, "(/x/) ... ($eof)"),
+        ] {
+            assert_eq!(condition(address(expr), mode), expected, "{expr}");
+        }
+    }
+}
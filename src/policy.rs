@@ -0,0 +1,265 @@
+//! What an expression may do beyond substituting text.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::subst::{Operator, Substitution};
+
+/// Constructs a chat or the whole bot allows. Everything is allowed by
+/// default, which is plain perl.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    /// `/e` and `/ee`, which run the replacement as code.
+    pub eval: bool,
+    /// Code inside the pattern or replacement: `(?{ })`, `(??{ })`, and
+    /// interpolated expressions like `@{[ ]}` or `$x{...}`.
+    pub code_blocks: bool,
+    /// Statements after the expression, `s/a/b/; ...`.
+    pub statements: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            eval: true,
+            code_blocks: true,
+            statements: true,
+        }
+    }
+}
+
+/// Why an expression was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// Number of `e` modifiers.
+    Eval(usize),
+    CodeBlock,
+    Statements,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Eval(1) => f.write_str("the /e modifier is not allowed here"),
+            Self::Eval(_) => f.write_str("the /ee modifier is not allowed here"),
+            Self::CodeBlock => {
+                f.write_str("code in the pattern or replacement is not allowed here")
+            }
+            Self::Statements => f.write_str("statements after the expression are not allowed here"),
+        }
+    }
+}
+
+impl Policy {
+    /// Only substitutions and transliterations, no code of any kind.
+    pub const PURE: Self = Self {
+        eval: false,
+        code_blocks: false,
+        statements: false,
+    };
+
+    /// Allows only what both `self` and `other` allow.
+    pub fn intersect(self, other: Self) -> Self {
+        Self {
+            eval: self.eval && other.eval,
+            code_blocks: self.code_blocks && other.code_blocks,
+            statements: self.statements && other.statements,
+        }
+    }
+
+    pub fn check(&self, subst: &Substitution<'_>) -> Result<(), Violation> {
+        if !self.eval && subst.modifiers.eval > 0 {
+            return Err(Violation::Eval(subst.modifiers.eval));
+        }
+        // Transliterations don't interpolate.
+        let interpolated = match subst.operator {
+            Operator::Transliterate => [].as_slice(),
+            _ => &[subst.pattern, subst.replacement],
+        };
+        let address = subst.address.iter().flat_map(|address| address.patterns());
+        if !self.code_blocks && address.chain(interpolated.iter().copied()).any(has_code) {
+            return Err(Violation::CodeBlock);
+        }
+        if !self.statements && !subst.tail.is_empty() {
+            return Err(Violation::Statements);
+        }
+        Ok(())
+    }
+}
+
+/// Whether the subscripts after an interpolated variable may run code.
+/// `{name}` and `[0]` can't, anything fancier might. Jobs get the feature
+/// bundle of their perl, which interpolates postfix slices like `->@[0]`.
+fn code_in_subscripts(mut rest: &str) -> bool {
+    loop {
+        let mut subscript = rest.strip_prefix("->").unwrap_or(rest);
+        if subscript.len() < rest.len() {
+            if let Some(slice) = subscript.strip_prefix('@') {
+                if slice.starts_with(['[', '{']) {
+                    subscript = slice;
+                }
+            }
+        }
+        let (close, simple): (char, fn(char) -> bool) = match subscript.chars().next() {
+            Some('{') => ('}', |c| c.is_alphanumeric() || c == '_'),
+            Some('[') => (']', |c| c.is_ascii_digit() || c == '-'),
+            // Method calls don't interpolate.
+            _ => return false,
+        };
+        let inner = &subscript[1..];
+        let len = inner.find(|c| !simple(c)).unwrap_or(inner.len());
+        if len == 0 || !inner[len..].starts_with(close) {
+            return true;
+        }
+        rest = &inner[len + 1..];
+    }
+}
+
+/// Whether interpolating `part` may run code. Errs on the side of caution.
+fn has_code(part: &str) -> bool {
+    const CODE_GROUPS: &[&str] = &["(?{", "(??{", "(*{"];
+
+    let mut chars = part.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '(' if CODE_GROUPS
+                .iter()
+                .any(|group| part[idx..].starts_with(group)) =>
+            {
+                return true
+            }
+            '$' | '@' => {
+                let rest = &part[idx + 1..];
+                // `${name}` is just a variable, `${\ ...}` and `@{[ ...]}`
+                // are not.
+                if let Some(inner) = rest.strip_prefix('{') {
+                    let name_len = inner
+                        .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '^'))
+                        .unwrap_or(inner.len());
+                    if !inner[name_len..].starts_with('}') {
+                        return true;
+                    }
+                    if code_in_subscripts(&inner[name_len + 1..]) {
+                        return true;
+                    }
+                    continue;
+                }
+
+                // Punctuation variables like `$+{name}` and `$-[0]` can be
+                // subscripted too. `$::{...}` is the `main::` stash, not a
+                // variable named `:`.
+                let name_len = match rest.chars().next() {
+                    Some(c) if c.is_ascii_punctuation() && c != '_' && !rest.starts_with("::") => 1,
+                    _ => rest
+                        .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':'))
+                        .unwrap_or(rest.len()),
+                };
+                if name_len > 0 && code_in_subscripts(&rest[name_len..]) {
+                    return true;
+                }
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subst;
+
+    #[test]
+    fn interpolation_that_runs_code() {
+        for part in [
+            "(?{ system 'id' })",
+            "(??{ 'a' })",
+            "(*{ 1 })",
+            "@{[ `id` ]}",
+            "${\\ `id`}",
+            "$x{`id`}",
+            "$x->{`id`}",
+            "$x[`id`]",
+            "$x{a}{`id`}",
+            "$+{`id`}",
+            "$-[`id`]",
+            "$::{`id`}",
+            "@::{`id`}",
+            "$main::{`id`}",
+            "$x::{`id`}",
+            "${x}{`id`}",
+            "$x->@[ print(STDERR \"CODE RAN\\n\"), 0 ]",
+            "$x->@{ `id` }",
+            "${x}->@{ `id` }",
+            "$x->[0]->@[`id`]",
+        ] {
+            assert!(has_code(part), "{part:?} should count as code");
+        }
+    }
+
+    #[test]
+    fn plain_interpolation() {
+        for part in [
+            "plain text",
+            "$1 and $2",
+            "${1}0",
+            "$&",
+            "$x",
+            "$x{name}",
+            "$x[0]",
+            "$x[-1]",
+            "$x->[0]{name}",
+            "$+{name}",
+            "$-[0]",
+            "${name}",
+            "@x",
+            "$x->method",
+            "$x->@[0]",
+            "$x->@{name}",
+            "$x->@*",
+            "$x->$#*",
+            "\\@{[ `id` ]}",
+            "\\(?{ 1 })",
+            "$",
+            "(?:a|b)",
+        ] {
+            assert!(!has_code(part), "{part:?} shouldn't count as code");
+        }
+    }
+
+    #[test]
+    fn pure_policy() {
+        let check = |expr| Policy::PURE.check(&subst::parse(expr).unwrap());
+        assert_eq!(check("s/a/b/g"), Ok(()));
+        assert_eq!(check("tr/a-z/A-Z/"), Ok(()));
+        assert_eq!(check("tr/${x}{`id`}//"), Ok(()));
+        assert_eq!(check("s/a/b/e"), Err(Violation::Eval(1)));
+        assert_eq!(check("s/a/b/ee"), Err(Violation::Eval(2)));
+        assert_eq!(check("s/a/$::{`id`}/"), Err(Violation::CodeBlock));
+        assert_eq!(
+            check("s/a/$x->@[ print(STDERR \"CODE RAN\\n\"), 0 ]/"),
+            Err(Violation::CodeBlock)
+        );
+        assert_eq!(check("/$x->@[ `id`, 0 ]/"), Err(Violation::CodeBlock));
+        assert_eq!(check("s/a/${x}->@{ `id` }/"), Err(Violation::CodeBlock));
+        assert_eq!(check("s/(?{ 1 })a/b/"), Err(Violation::CodeBlock));
+        assert_eq!(check("m/(??{ 'a' })/"), Err(Violation::CodeBlock));
+        assert_eq!(check("/(?{ 1 })/s/a/b/"), Err(Violation::CodeBlock));
+        assert_eq!(check("s/a/b/; print 1"), Err(Violation::Statements));
+    }
+
+    #[test]
+    fn intersect() {
+        let policy = Policy::default().intersect(Policy {
+            eval: false,
+            ..Policy::default()
+        });
+        assert!(!policy.eval && policy.code_blocks && policy.statements);
+        assert_eq!(Policy::PURE.intersect(Policy::default()), Policy::PURE);
+    }
+}
@@ -0,0 +1,374 @@
+//! Pool of warm, sandboxed perl workers.
+//!
+//! Every worker is a perl "zygote" running `zygote.pl` inside the sandbox. It
+//! forks a fresh child for every job, so runs are still isolated from each
+//! other while the cost of starting the sandbox and perl is only paid once
+//! per worker.
+
+use std::{
+    io,
+    os::unix::process::ExitStatusExt as _,
+    process::{ExitStatus, Stdio},
+    sync::Mutex,
+    time::Duration,
+};
+
+use color_eyre::eyre;
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _, BufReader},
+    process::{Child, ChildStdin, ChildStdout, Command},
+    sync::Semaphore,
+};
+
+use crate::{
+    cgroup::{Cgroups, PeakMemory, Usage, WorkerCgroup},
+    config::Config,
+    limits::{self, Limits},
+    sandbox::{Sandbox, SeccompFilter},
+};
+
+const ZYGOTE: &str = include_str!("zygote.pl");
+
+/// How much of a run's stderr is kept for error reporting.
+const STDERR_CAP: usize = 4096;
+
+/// A single perl run.
+pub struct Job<'a> {
+    /// Program the child evaluates, with the expressions in it.
+    pub code: &'a str,
+    /// What the program reads from stdin.
+    pub input: &'a str,
+    pub limits: &'a Limits,
+    pub max_output_bytes: usize,
+    /// Opcodes the Safe compartment permits, `None` to run without one.
+    pub opcodes: Option<&'a [String]>,
+}
+
+/// How a run ended.
+pub enum Exit {
+    Exited {
+        status: ExitStatus,
+        stdout: Vec<u8>,
+        /// `stdout` was cut at `max_output_bytes`.
+        truncated: bool,
+        stderr: Vec<u8>,
+        /// The OOM killer fired in the worker's cgroup during the run.
+        oom_killed: bool,
+        /// User and system time of the run, in clock ticks.
+        cpu_time: Duration,
+    },
+    TimedOut,
+}
+
+impl Exit {
+    /// Whether the worker can be trusted with another job afterwards. A
+    /// run killed by a signal may not have read its whole job, and a timed
+    /// out one is still running.
+    fn worker_reusable(&self) -> bool {
+        match self {
+            Self::Exited { status, .. } => status.signal().is_none(),
+            Self::TimedOut => false,
+        }
+    }
+}
+
+/// A running zygote.
+#[derive(Debug)]
+struct Worker {
+    child: Child,
+    pid: u32,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    cgroup: Option<WorkerCgroup>,
+    jobs: u64,
+    reaped: bool,
+}
+
+impl Worker {
+    async fn run(&mut self, job: &Job<'_>) -> io::Result<Exit> {
+        self.jobs += 1;
+        let (before, mut peak) = match &self.cgroup {
+            Some(cgroup) => {
+                cgroup.set_limits(job.limits)?;
+                (cgroup.usage(), cgroup.reset_peak())
+            }
+            None => (Usage::default(), None),
+        };
+
+        let rlimits = job
+            .limits
+            .rlimits()
+            .iter()
+            .map(|(resource, limit)| format!("{resource}={limit}"))
+            .collect::<Vec<_>>()
+            .join(",");
+        let opcodes = match job.opcodes {
+            Some(opcodes) => opcodes.join(","),
+            None => "-".to_owned(),
+        };
+        let header = format!(
+            "{} {} {rlimits} {opcodes}\n",
+            job.code.len(),
+            job.input.len(),
+        );
+        let stdin = &mut self.stdin;
+        let stdout = &mut self.stdout;
+        let write = async {
+            stdin.write_all(header.as_bytes()).await?;
+            stdin.write_all(job.code.as_bytes()).await?;
+            stdin.write_all(job.input.as_bytes()).await?;
+            stdin.flush().await
+        };
+        let run = async {
+            let ((), exit) = tokio::try_join!(write, read_frames(stdout, job.max_output_bytes),)?;
+            io::Result::Ok(exit)
+        };
+        let Ok(exit) = tokio::time::timeout(job.limits.timeout(), run).await else {
+            return Ok(Exit::TimedOut);
+        };
+        let (status, cpu_time, stdout, truncated, stderr) = exit?;
+
+        let mut oom_killed = false;
+        if let Some(cgroup) = &self.cgroup {
+            let after = cgroup.usage();
+            oom_killed = after.oom_kills > before.oom_kills;
+            tracing::debug!(
+                peak_memory = ?peak.as_mut().and_then(PeakMemory::read),
+                cpu_time = ?after.cpu_time.zip(before.cpu_time).map(|(a, b)| a - b),
+                oom_killed,
+                "perl resource usage",
+            );
+        }
+        Ok(Exit::Exited {
+            status,
+            stdout,
+            truncated,
+            stderr,
+            oom_killed,
+            cpu_time,
+        })
+    }
+
+    /// Stops the worker, giving it `grace` to exit after SIGTERM.
+    async fn retire(mut self, grace: Duration) {
+        limits::kill_group(self.pid, libc::SIGTERM);
+        if tokio::time::timeout(grace, self.child.wait())
+            .await
+            .is_err()
+        {
+            limits::kill_group(self.pid, libc::SIGKILL);
+        }
+        if let Err(err) = self.child.wait().await {
+            tracing::warn!(%err, pid = self.pid, "failed to wait for worker");
+        }
+        self.reaped = true;
+
+        if let Some(cgroup) = self.cgroup.take() {
+            cgroup.remove().await;
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if !self.reaped {
+            limits::kill_group(self.pid, libc::SIGKILL);
+        }
+        if let Some(cgroup) = self.cgroup.take() {
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                runtime.spawn(cgroup.remove());
+            }
+        }
+    }
+}
+
+/// Reads frames until the exit one, keeping the first `cap` bytes of stdout
+/// and [`STDERR_CAP`] of stderr.
+async fn read_frames(
+    reader: &mut BufReader<ChildStdout>,
+    cap: usize,
+) -> io::Result<(ExitStatus, Duration, Vec<u8>, bool, Vec<u8>)> {
+    let mut stdout = Vec::new();
+    let mut truncated = false;
+    let mut stderr = Vec::new();
+    let mut buf = Vec::new();
+    loop {
+        let tag = reader.read_u8().await?;
+        if tag == b'x' {
+            let status = ExitStatus::from_raw(reader.read_u32().await? as i32);
+            let cpu_time = Duration::from_millis(reader.read_u32().await?.into());
+            return Ok((status, cpu_time, stdout, truncated, stderr));
+        }
+
+        let len = reader.read_u32().await? as usize;
+        buf.resize(len, 0);
+        reader.read_exact(&mut buf).await?;
+        let (out, cap) = match tag {
+            b'o' => (&mut stdout, cap),
+            b'e' => (&mut stderr, STDERR_CAP),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected frame {tag:#04x} from worker"),
+                ))
+            }
+        };
+        let keep = len.min(cap - out.len());
+        truncated |= tag == b'o' && keep < len;
+        out.extend_from_slice(&buf[..keep]);
+    }
+}
+
+/// Up to `max_parallel` workers, each running one job at a time. Jobs wait
+/// for a permit first, then take an idle worker or start a new one.
+#[derive(Debug)]
+pub struct Pool {
+    sandbox: Box<dyn Sandbox>,
+    cgroups: Option<Cgroups>,
+    /// Command line of a worker, sandbox included.
+    argv: Vec<std::ffi::OsString>,
+    /// Limits a worker's cgroup starts out with.
+    limits: Limits,
+    max_jobs: u64,
+    permits: Semaphore,
+    idle: Mutex<Vec<Worker>>,
+}
+
+impl Pool {
+    /// Creates the pool and starts `pool_prestart` workers.
+    pub fn new(
+        cfg: &Config,
+        sandbox: Box<dyn Sandbox>,
+        cgroups: Option<Cgroups>,
+    ) -> eyre::Result<Self> {
+        let filter = SeccompFilter::from_config(cfg)?;
+        let mut argv = sandbox.argv(&cfg.perl);
+        argv.extend(["-e".into(), ZYGOTE.into(), "--".into()]);
+        argv.extend(
+            [
+                libc::SYS_prctl,
+                libc::SYS_seccomp,
+                libc::SYS_prlimit64,
+                libc::FIONREAD as libc::c_long,
+            ]
+            .map(|arg| arg.to_string().into()),
+        );
+        if let Some(filter) = filter {
+            let hex = filter
+                .to_bytes()
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<String>();
+            argv.push(hex.into());
+        }
+
+        let pool = Self {
+            sandbox,
+            cgroups,
+            argv,
+            limits: cfg.line_limits,
+            max_jobs: cfg.worker_max_jobs,
+            permits: Semaphore::new(cfg.max_parallel),
+            idle: Mutex::new(Vec::new()),
+        };
+        let prestart = cfg.pool_prestart.min(cfg.max_parallel);
+        let workers = (0..prestart)
+            .map(|_| pool.spawn())
+            .collect::<io::Result<Vec<_>>>()?;
+        *pool.idle.lock().unwrap() = workers;
+        tracing::info!(workers = prestart, "worker pool is ready");
+        Ok(pool)
+    }
+
+    fn spawn(&self) -> io::Result<Worker> {
+        let mut cmd = Command::new(&self.argv[0]);
+
+        #[rustfmt::skip]
+        cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .env_clear()
+        .env("LANG", "C")
+        .kill_on_drop(true)
+        .args(&self.argv[1..]);
+
+        // SAFETY: `limits::pre_exec` is async-signal-safe.
+        unsafe {
+            cmd.pre_exec(limits::pre_exec);
+        }
+        let cgroup = match self
+            .cgroups
+            .as_ref()
+            .map(|cgroups| cgroups.create(&self.limits))
+        {
+            Some(Ok(cgroup)) => {
+                cgroup.enter(&mut cmd)?;
+                Some(cgroup)
+            }
+            Some(Err(err)) => {
+                tracing::warn!(%err, "failed to create cgroup, running with rlimits only");
+                None
+            }
+            None => None,
+        };
+        // Hooks run in order, and the sandbox may install a seccomp filter that
+        // forbids what the others do.
+        self.sandbox.prepare(&mut cmd)?;
+
+        let mut child = cmd.spawn()?;
+        let pid = child.id().expect("child was not polled yet");
+        tracing::debug!(pid, "started worker");
+        Ok(Worker {
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child,
+            pid,
+            cgroup,
+            jobs: 0,
+            reaped: false,
+        })
+    }
+
+    /// Takes an idle worker, or starts a new one if there is none.
+    fn checkout(&self) -> io::Result<Worker> {
+        loop {
+            let Some(mut worker) = self.idle.lock().unwrap().pop() else {
+                return self.spawn();
+            };
+            match worker.child.try_wait() {
+                Ok(None) => return Ok(worker),
+                Ok(Some(status)) => {
+                    tracing::warn!(pid = worker.pid, %status, "idle worker exited");
+                    worker.reaped = true;
+                }
+                Err(err) => tracing::warn!(%err, pid = worker.pid, "idle worker is unusable"),
+            }
+        }
+    }
+
+    /// Puts `worker` back, or replaces it if it shouldn't run any more jobs.
+    fn checkin(&self, worker: Worker, reusable: bool, grace: Duration) {
+        if reusable && worker.jobs < self.max_jobs {
+            self.idle.lock().unwrap().push(worker);
+            return;
+        }
+
+        tracing::debug!(pid = worker.pid, jobs = worker.jobs, "retiring worker");
+        tokio::spawn(worker.retire(grace));
+        match self.spawn() {
+            Ok(worker) => self.idle.lock().unwrap().push(worker),
+            Err(err) => tracing::warn!(%err, "failed to start replacement worker"),
+        }
+    }
+
+    /// Runs `job` on a worker, waiting for one to become available first.
+    pub async fn run(&self, job: Job<'_>) -> eyre::Result<Exit> {
+        let _permit = self.permits.acquire().await?;
+        let mut worker = self.checkout()?;
+        let res = worker.run(&job).await;
+        let reusable = res.as_ref().is_ok_and(Exit::worker_reusable);
+        self.checkin(worker, reusable, job.limits.kill_after());
+        Ok(res?)
+    }
+}
@@ -0,0 +1,58 @@
+use std::{
+    ffi::OsString,
+    io,
+    os::fd::RawFd,
+    path::{Path, PathBuf},
+};
+
+use tokio::process::Command;
+
+use super::{inherit_fd, Sandbox, SeccompFilter};
+
+/// Descriptor the seccomp program is passed to bwrap on.
+const SECCOMP_FD: RawFd = 3;
+
+/// bubblewrap with every namespace unshared and `allow_dirs` bound read-only.
+/// The seccomp filter is loaded by bwrap right before it execs perl.
+#[derive(Debug)]
+pub struct Bwrap {
+    bwrap: PathBuf,
+    allow_dirs: Vec<PathBuf>,
+    seccomp: Option<SeccompFilter>,
+}
+
+impl Bwrap {
+    pub fn new(bwrap: PathBuf, allow_dirs: Vec<PathBuf>, seccomp: Option<SeccompFilter>) -> Self {
+        Self {
+            bwrap,
+            allow_dirs,
+            seccomp,
+        }
+    }
+}
+
+impl Sandbox for Bwrap {
+    fn argv(&self, perl: &Path) -> Vec<OsString> {
+        let mut argv: Vec<OsString> = vec![self.bwrap.clone().into()];
+        argv.extend(
+            ["--unshare-all", "--proc", "/proc", "--dev", "/dev"]
+                .into_iter()
+                .map(OsString::from),
+        );
+        for dir in &self.allow_dirs {
+            argv.extend(["--ro-bind".into(), dir.into(), dir.into()]);
+        }
+        if self.seccomp.is_some() {
+            argv.extend(["--seccomp".into(), SECCOMP_FD.to_string().into()]);
+        }
+        argv.push(perl.into());
+        argv
+    }
+
+    fn prepare(&self, cmd: &mut Command) -> io::Result<()> {
+        if let Some(seccomp) = &self.seccomp {
+            inherit_fd(cmd, seccomp.memfd()?, SECCOMP_FD);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+//! Landlock-based sandbox for hosts without unprivileged user namespaces.
+
+use std::{
+    ffi::OsString,
+    fs::File,
+    io,
+    os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use color_eyre::eyre::{self, WrapErr as _};
+use tokio::process::Command;
+
+use super::{Sandbox, SeccompFilter};
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+
+/// Everything ABI v1 knows about, rights added later are only handled (and
+/// thus denied) when the kernel supports them.
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+/// `LANDLOCK_ACCESS_FS_REFER`.
+const ACCESS_FS_V2: u64 = 1 << 13;
+/// `LANDLOCK_ACCESS_FS_TRUNCATE`.
+const ACCESS_FS_V3: u64 = 1 << 14;
+
+/// What `allow_dirs` are granted, same as bwrap's `--ro-bind`.
+const ACCESS_FS_READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+
+/// Device files bwrap's `--dev` provides that perl relies on, e.g. `-e` is
+/// implemented by reading the script from `/dev/null`.
+const DEVICES: &[&str] = &["/dev/null", "/dev/urandom"];
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Landlock ABI version supported by the running kernel, `None` if Landlock
+/// is unavailable.
+pub fn abi_version() -> Option<i64> {
+    // SAFETY: with the VERSION flag the attribute pointer must be null.
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    (res > 0).then_some(res)
+}
+
+/// Ruleset that only allows reading and executing from `allow_dirs`.
+fn create_ruleset(abi: i64, allow_dirs: &[PathBuf]) -> eyre::Result<OwnedFd> {
+    let mut handled = ACCESS_FS_V1;
+    if abi >= 2 {
+        handled |= ACCESS_FS_V2;
+    }
+    if abi >= 3 {
+        handled |= ACCESS_FS_V3;
+    }
+
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    // SAFETY: `attr` is a valid ruleset attribute of the given size.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).wrap_err("failed to create landlock ruleset");
+    }
+    // SAFETY: the syscall returned a new descriptor.
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+    let devices = DEVICES
+        .iter()
+        .map(|&dev| (Path::new(dev), ACCESS_FS_READ_FILE));
+    let dirs = allow_dirs.iter().map(|dir| (dir.as_path(), ACCESS_FS_READ));
+    for (dir, mut allowed_access) in devices.chain(dirs) {
+        let parent = File::open(dir).wrap_err_with(|| format!("failed to open {dir:?}"))?;
+        if !parent.metadata()?.is_dir() {
+            // Directory rights can't be granted on files.
+            allowed_access &= !ACCESS_FS_READ_DIR;
+        }
+        let rule = PathBeneathAttr {
+            allowed_access,
+            parent_fd: parent.as_raw_fd(),
+        };
+        // SAFETY: `rule` is a valid path beneath attribute.
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &rule,
+                0,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error())
+                .wrap_err_with(|| format!("failed to add landlock rule for {dir:?}"));
+        }
+    }
+
+    Ok(ruleset)
+}
+
+/// No namespaces: perl runs directly with a Landlock ruleset restricting the
+/// filesystem to `allow_dirs`, read-only. Networking is left to the seccomp
+/// filter, so running without it is a lot weaker than bwrap.
+#[derive(Debug)]
+pub struct Landlock {
+    ruleset: Arc<OwnedFd>,
+    seccomp: Option<Arc<SeccompFilter>>,
+}
+
+impl Landlock {
+    pub fn new(
+        abi: i64,
+        allow_dirs: &[PathBuf],
+        seccomp: Option<SeccompFilter>,
+    ) -> eyre::Result<Self> {
+        Ok(Self {
+            ruleset: Arc::new(create_ruleset(abi, allow_dirs)?),
+            seccomp: seccomp.map(Arc::new),
+        })
+    }
+}
+
+impl Sandbox for Landlock {
+    fn argv(&self, perl: &Path) -> Vec<OsString> {
+        vec![perl.into()]
+    }
+
+    fn prepare(&self, cmd: &mut Command) -> io::Result<()> {
+        let ruleset = self.ruleset.clone();
+        let seccomp = self.seccomp.clone();
+        let hook = move || {
+            // SAFETY: plain syscalls with valid arguments.
+            unsafe {
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+                    || libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            // Last, since the filter doesn't allow any of the above.
+            match &seccomp {
+                Some(seccomp) => seccomp.install(),
+                None => Ok(()),
+            }
+        };
+        // SAFETY: the hook only calls async-signal-safe functions.
+        unsafe {
+            cmd.pre_exec(hook);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    ffi::OsString,
+    fmt, io,
+    os::fd::{AsRawFd as _, OwnedFd, RawFd},
+    path::Path,
+    process::Stdio,
+};
+
+use color_eyre::eyre::{self, bail, eyre};
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::config::Config;
+
+mod bwrap;
+mod landlock;
+mod mounts;
+mod nsjail;
+mod seccomp;
+
+pub use self::{
+    bwrap::Bwrap, landlock::Landlock, mounts::discover_mounts, nsjail::Nsjail,
+    seccomp::SeccompFilter,
+};
+
+/// Which [`Sandbox`] implementation to use.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SandboxKind {
+    /// bwrap if user namespaces work, Landlock otherwise.
+    #[default]
+    Auto,
+    Bwrap,
+    Nsjail,
+    Landlock,
+}
+
+/// Isolates the perl process from the host.
+///
+/// Perl runs inside it as a long-lived zygote that forks a child per run, see
+/// `crate::pool`. Wall-clock time and cgroup limits are enforced from the
+/// outside and per-run rlimits by the zygote, the sandbox itself is
+/// responsible for filesystem and namespace isolation.
+pub trait Sandbox: fmt::Debug + Send + Sync {
+    /// Command line that runs `perl` inside the sandbox, program first. Perl
+    /// arguments are appended to it by the caller.
+    fn argv(&self, perl: &Path) -> Vec<OsString>;
+
+    /// Called for every run before the command is spawned, e.g. to pass file
+    /// descriptors referenced by [`Sandbox::argv`].
+    fn prepare(&self, _cmd: &mut Command) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Makes `fd` available to the child as `target`, without CLOEXEC.
+fn inherit_fd(cmd: &mut Command, fd: OwnedFd, target: RawFd) {
+    let hook = move || {
+        let raw = fd.as_raw_fd();
+        // SAFETY: both are plain syscalls on descriptors we own in the child.
+        let res = if raw == target {
+            unsafe { libc::fcntl(raw, libc::F_SETFD, 0) }
+        } else {
+            unsafe { libc::dup2(raw, target) }
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    };
+    // SAFETY: the hook only calls async-signal-safe functions.
+    unsafe {
+        cmd.pre_exec(hook);
+    }
+}
+
+/// Checks that `sandbox` can run perl at all.
+async fn probe(sandbox: &dyn Sandbox, perl: &Path) -> bool {
+    let argv = sandbox.argv(perl);
+    let mut cmd = Command::new(&argv[0]);
+    cmd.args(&argv[1..])
+        .args(["-e", "1"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    if let Err(err) = sandbox.prepare(&mut cmd) {
+        tracing::debug!(%err, "sandbox probe failed");
+        return false;
+    }
+
+    match cmd.output().await {
+        Ok(output) if output.status.success() => true,
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            tracing::debug!(status = %output.status, %stderr, "sandbox probe failed");
+            false
+        }
+        Err(err) => {
+            tracing::debug!(%err, "sandbox probe failed");
+            false
+        }
+    }
+}
+
+fn bwrap(cfg: &Config, seccomp: Option<SeccompFilter>) -> eyre::Result<Bwrap> {
+    Ok(Bwrap::new(
+        cfg.bwrap
+            .clone()
+            .ok_or_else(|| eyre!("BWRAP must be set to use the bwrap sandbox"))?,
+        cfg.allow_dirs.clone(),
+        seccomp,
+    ))
+}
+
+fn landlock(cfg: &Config, seccomp: Option<SeccompFilter>) -> eyre::Result<Landlock> {
+    let abi = match landlock::abi_version() {
+        Some(abi) => abi,
+        None => bail!("landlock is not supported by the kernel"),
+    };
+    tracing::info!(abi, "landlock is available");
+    Landlock::new(abi, &cfg.allow_dirs, seccomp)
+}
+
+pub async fn from_config(cfg: &Config) -> eyre::Result<Box<dyn Sandbox>> {
+    // The zygote hands the plain allowlist to every run it forks.
+    let seccomp = match SeccompFilter::from_config(cfg)? {
+        Some(seccomp) => Some(seccomp.for_zygote()?),
+        None => {
+            tracing::warn!("seccomp filter is disabled");
+            None
+        }
+    };
+
+    let sandbox: Box<dyn Sandbox> = match cfg.sandbox {
+        SandboxKind::Auto => 'auto: {
+            if cfg.bwrap.is_some() {
+                let bwrap = bwrap(cfg, seccomp.clone())?;
+                if probe(&bwrap, &cfg.perl).await {
+                    break 'auto Box::new(bwrap);
+                }
+                tracing::warn!("bwrap doesn't work here, falling back to landlock");
+            }
+            Box::new(landlock(cfg, seccomp)?)
+        }
+        SandboxKind::Bwrap => Box::new(bwrap(cfg, seccomp)?),
+        SandboxKind::Nsjail => Box::new(Nsjail {
+            nsjail: cfg
+                .nsjail
+                .clone()
+                .ok_or_else(|| eyre!("NSJAIL must be set to use the nsjail sandbox"))?,
+            allow_dirs: cfg.allow_dirs.clone(),
+            seccomp,
+        }),
+        SandboxKind::Landlock => Box::new(landlock(cfg, seccomp)?),
+    };
+    tracing::info!(?sandbox, "sandbox is ready");
+    Ok(sandbox)
+}
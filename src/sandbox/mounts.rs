@@ -0,0 +1,281 @@
+//! Works out what perl needs mounted from its ELF dependencies and `@INC`.
+
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Component, Path, PathBuf},
+    process::Command,
+};
+
+use color_eyre::eyre::{self, bail, ensure, eyre, WrapErr as _};
+
+use crate::config::Config;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+
+const NIX_STORE: &str = "/nix/store";
+
+/// The parts of a dynamically linked ELF object we care about.
+#[derive(Debug, Default)]
+struct Elf {
+    interpreter: Option<PathBuf>,
+    needed: Vec<String>,
+    search_path: Vec<PathBuf>,
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+fn read_cstr(data: &[u8], at: usize) -> Option<&str> {
+    let rest = data.get(at..)?;
+    let len = rest.iter().position(|&b| b == 0)?;
+    std::str::from_utf8(&rest[..len]).ok()
+}
+
+struct ProgramHeader {
+    kind: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+}
+
+/// Parses a 64-bit little-endian ELF object, which covers every target the
+/// seccomp filter supports.
+fn parse_elf(path: &Path) -> eyre::Result<Elf> {
+    let data = fs::read(path).wrap_err_with(|| format!("failed to read {path:?}"))?;
+    ensure!(data.starts_with(b"\x7fELF"), "{path:?} is not an ELF file");
+    ensure!(
+        data.get(4..6) == Some(&[2, 1]),
+        "{path:?} is not a 64-bit little-endian ELF file"
+    );
+    let truncated = || eyre!("{path:?} is truncated");
+
+    let phoff = read_u64(&data, 0x20).ok_or_else(truncated)? as usize;
+    let phentsize = read_u16(&data, 0x36).ok_or_else(truncated)? as usize;
+    let phnum = read_u16(&data, 0x38).ok_or_else(truncated)? as usize;
+    let headers = (0..phnum)
+        .map(|idx| {
+            let at = phoff + idx * phentsize;
+            Some(ProgramHeader {
+                kind: read_u32(&data, at)?,
+                offset: read_u64(&data, at + 8)?,
+                vaddr: read_u64(&data, at + 16)?,
+                filesz: read_u64(&data, at + 32)?,
+            })
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(truncated)?;
+
+    let to_offset = |addr: u64| {
+        headers
+            .iter()
+            .filter(|ph| ph.kind == PT_LOAD)
+            .find(|ph| (ph.vaddr..ph.vaddr + ph.filesz).contains(&addr))
+            .map(|ph| (addr - ph.vaddr + ph.offset) as usize)
+    };
+
+    let mut elf = Elf::default();
+    if let Some(ph) = headers.iter().find(|ph| ph.kind == PT_INTERP) {
+        let interpreter = read_cstr(&data, ph.offset as usize).ok_or_else(truncated)?;
+        elf.interpreter = Some(interpreter.into());
+    }
+
+    let Some(dynamic) = headers.iter().find(|ph| ph.kind == PT_DYNAMIC) else {
+        // Statically linked.
+        return Ok(elf);
+    };
+    let mut strtab = None;
+    let mut needed = Vec::new();
+    let mut rpath = None;
+    let mut runpath = None;
+    for at in (dynamic.offset..dynamic.offset + dynamic.filesz).step_by(16) {
+        let at = at as usize;
+        let tag = read_u64(&data, at).ok_or_else(truncated)?;
+        let val = read_u64(&data, at + 8).ok_or_else(truncated)?;
+        match tag {
+            DT_NULL => break,
+            DT_NEEDED => needed.push(val),
+            DT_STRTAB => strtab = to_offset(val),
+            DT_RPATH => rpath = Some(val),
+            DT_RUNPATH => runpath = Some(val),
+            _ => {}
+        }
+    }
+
+    let Some(strtab) = strtab else {
+        bail!("{path:?} has no string table");
+    };
+    let string = |offset: u64| {
+        read_cstr(&data, strtab + offset as usize)
+            .map(str::to_owned)
+            .ok_or_else(truncated)
+    };
+    for offset in needed {
+        elf.needed.push(string(offset)?);
+    }
+    // RPATH is ignored by the loader when RUNPATH is present.
+    if let Some(offset) = runpath.or(rpath) {
+        let origin = path.parent().unwrap_or(Path::new("/"));
+        let origin = origin.to_string_lossy();
+        elf.search_path = string(offset)?
+            .split(':')
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| dir.replace("$ORIGIN", &origin).into())
+            .collect();
+    }
+    Ok(elf)
+}
+
+/// Directories the loader searches when nothing else matched.
+fn default_search_path(interpreter: Option<&Path>) -> Vec<PathBuf> {
+    let arch = std::env::consts::ARCH;
+    let mut dirs: Vec<PathBuf> = interpreter
+        .and_then(Path::parent)
+        .map(Path::to_owned)
+        .into_iter()
+        .collect();
+    dirs.extend(
+        [
+            format!("/lib/{arch}-linux-gnu"),
+            format!("/usr/lib/{arch}-linux-gnu"),
+            "/lib64".to_owned(),
+            "/usr/lib64".to_owned(),
+            "/lib".to_owned(),
+            "/usr/lib".to_owned(),
+        ]
+        .map(PathBuf::from),
+    );
+    dirs
+}
+
+/// Every file the dynamic loader opens to run `exe`, besides `exe` itself.
+fn elf_closure(exe: &Path) -> eyre::Result<Vec<PathBuf>> {
+    let root = parse_elf(exe)?;
+    let defaults = default_search_path(root.interpreter.as_deref());
+
+    let mut files: Vec<PathBuf> = root.interpreter.clone().into_iter().collect();
+    let mut seen = BTreeSet::new();
+    let mut queue = vec![(exe.to_owned(), root)];
+    while let Some((path, elf)) = queue.pop() {
+        for name in &elf.needed {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+
+            let found = elf
+                .search_path
+                .iter()
+                .chain(&defaults)
+                .map(|dir| dir.join(name))
+                .find(|candidate| candidate.exists());
+            let Some(lib) = found else {
+                tracing::warn!(library = name, needed_by = ?path, "couldn't resolve library");
+                continue;
+            };
+            queue.push((lib.clone(), parse_elf(&lib)?));
+            files.push(lib);
+        }
+    }
+    Ok(files)
+}
+
+/// `@INC` as reported by `perl` itself.
+fn perl_inc(perl: &Path) -> eyre::Result<Vec<PathBuf>> {
+    let output = Command::new(perl)
+        .args(["-e", "print join qq(\\n), @INC"])
+        .env_clear()
+        .output()
+        .wrap_err_with(|| format!("failed to run {perl:?}"))?;
+    ensure!(
+        output.status.success(),
+        "{perl:?} failed: {}",
+        output.status
+    );
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute() && dir.exists())
+        .collect())
+}
+
+/// What perl needs a path for.
+#[derive(Debug, Clone, Copy)]
+enum Dependency {
+    /// The perl binary, whose directory is usually `/usr/bin`.
+    Executable,
+    /// The ELF interpreter or a shared library. Their directory is mounted
+    /// so that the symlinks between library names keep working.
+    Library,
+    /// An `@INC` directory.
+    Inc,
+}
+
+/// What needs to be mounted for `path` to be usable: the whole store path
+/// for Nix, the file itself for perl and the containing directory for
+/// libraries otherwise.
+fn mount_for(path: &Path, dependency: Dependency) -> PathBuf {
+    if let Ok(rest) = path.strip_prefix(NIX_STORE) {
+        if let Some(Component::Normal(name)) = rest.components().next() {
+            return Path::new(NIX_STORE).join(name);
+        }
+    }
+
+    match dependency {
+        Dependency::Executable | Dependency::Inc => path.to_owned(),
+        Dependency::Library => path.parent().unwrap_or(path).to_owned(),
+    }
+}
+
+/// Read-only mounts perl needs: its ELF interpreter and libraries, `@INC`,
+/// and the configured `allow_dirs`.
+/// Paths are also included in canonical form so that symlinks keep working
+/// inside the sandbox.
+pub fn discover_mounts(cfg: &Config) -> eyre::Result<Vec<PathBuf>> {
+    let exe = [(cfg.perl.clone(), Dependency::Executable)];
+    let libs = elf_closure(&cfg.perl)?
+        .into_iter()
+        .map(|file| (file, Dependency::Library));
+    let dirs = perl_inc(&cfg.perl)?
+        .into_iter()
+        .map(|dir| (dir, Dependency::Inc));
+
+    let mut mounts = BTreeSet::new();
+    for (path, dependency) in exe.into_iter().chain(libs).chain(dirs) {
+        if let Ok(canonical) = path.canonicalize() {
+            mounts.insert(mount_for(&canonical, dependency));
+        }
+        mounts.insert(mount_for(&path, dependency));
+    }
+    mounts.extend(cfg.allow_dirs.iter().cloned());
+
+    // Mounting a directory already covers everything below it.
+    let mounts: Vec<PathBuf> = mounts.into_iter().collect();
+    let mounts: Vec<PathBuf> = mounts
+        .iter()
+        .filter(|dir| {
+            !mounts
+                .iter()
+                .any(|other| other != *dir && dir.starts_with(other))
+        })
+        .cloned()
+        .collect();
+    tracing::info!(?mounts, "discovered sandbox mounts");
+    Ok(mounts)
+}
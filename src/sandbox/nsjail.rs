@@ -0,0 +1,48 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use super::{Sandbox, SeccompFilter};
+
+#[rustfmt::skip]
+const FLAGS: &[&str] = &[
+    "--mode", "o",
+    "--quiet",
+    "--keep_env",
+    "--time_limit", "0",
+    "--rlimit_fsize", "0",
+    // nsjail overrides these with its own defaults, keep what `run_perl` set.
+    "--rlimit_cpu", "soft",
+    "--rlimit_as", "soft",
+    "--bindmount_ro", "/dev/null",
+    "--bindmount_ro", "/dev/urandom",
+];
+
+/// nsjail in one-shot mode. It unshares every namespace by default, the
+/// seccomp allowlist is handed over as a kafel policy.
+#[derive(Debug)]
+pub struct Nsjail {
+    pub nsjail: PathBuf,
+    pub allow_dirs: Vec<PathBuf>,
+    pub seccomp: Option<SeccompFilter>,
+}
+
+impl Sandbox for Nsjail {
+    fn argv(&self, perl: &Path) -> Vec<OsString> {
+        let mut argv: Vec<OsString> = vec![self.nsjail.clone().into()];
+        argv.extend(FLAGS.iter().map(OsString::from));
+        for dir in &self.allow_dirs {
+            argv.extend(["--bindmount_ro".into(), dir.into()]);
+        }
+        if let Some(seccomp) = &self.seccomp {
+            argv.extend([
+                "--seccomp_log".into(),
+                "--seccomp_string".into(),
+                seccomp.kafel().into(),
+            ]);
+        }
+        argv.extend(["--".into(), perl.into()]);
+        argv
+    }
+}
@@ -0,0 +1,266 @@
+//! seccomp-bpf syscall allowlist for the perl process.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Seek as _, Write as _},
+    os::fd::{FromRawFd as _, OwnedFd},
+};
+
+use color_eyre::eyre::{self, bail};
+
+use crate::config::Config;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xC000_003E;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xC000_00B7;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("seccomp filter is only implemented for x86_64 and aarch64");
+
+/// Offsets into `struct seccomp_data`.
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+
+/// x32 syscalls on x86_64 have this bit set, they would otherwise bypass the
+/// filter since numbers are compared as is.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+macro_rules! syscalls {
+    ($($sys:ident),* $(,)?) => {
+        &[$((stringify!($sys), libc::$sys)),*]
+    };
+}
+
+/// Syscalls that can be named in the allowlist, with `SYS_` in front.
+#[rustfmt::skip]
+const KNOWN: &[(&str, libc::c_long)] = syscalls![
+    SYS_read, SYS_write, SYS_readv, SYS_writev, SYS_pread64, SYS_pwrite64,
+    SYS_openat, SYS_close, SYS_close_range, SYS_fstat, SYS_newfstatat, SYS_statx,
+    SYS_lseek, SYS_mmap, SYS_mprotect, SYS_munmap, SYS_mremap, SYS_madvise,
+    SYS_brk, SYS_rt_sigaction, SYS_rt_sigprocmask, SYS_rt_sigreturn,
+    SYS_sigaltstack, SYS_ioctl, SYS_faccessat, SYS_faccessat2, SYS_readlinkat,
+    SYS_getdents64, SYS_getcwd, SYS_fcntl, SYS_dup, SYS_dup3, SYS_pipe2,
+    SYS_getpid, SYS_gettid, SYS_getppid, SYS_getuid, SYS_geteuid, SYS_getgid,
+    SYS_getegid, SYS_getgroups, SYS_uname, SYS_sysinfo, SYS_getrusage,
+    SYS_times, SYS_clock_gettime, SYS_clock_getres, SYS_clock_nanosleep,
+    SYS_gettimeofday, SYS_nanosleep, SYS_futex, SYS_set_tid_address,
+    SYS_set_robust_list, SYS_rseq, SYS_prlimit64, SYS_getrandom,
+    SYS_sched_getaffinity, SYS_sched_yield, SYS_execve, SYS_exit,
+    SYS_exit_group, SYS_wait4, SYS_kill, SYS_tgkill, SYS_clone, SYS_clone3,
+    SYS_socket, SYS_connect, SYS_sendto, SYS_recvfrom, SYS_mkdirat,
+    SYS_unlinkat, SYS_renameat, SYS_ftruncate, SYS_fsync, SYS_umask,
+    SYS_chdir, SYS_fchdir, SYS_ptrace, SYS_mount, SYS_setrlimit, SYS_prctl,
+    SYS_seccomp, SYS_pselect6, SYS_ppoll,
+];
+
+#[cfg(target_arch = "x86_64")]
+#[rustfmt::skip]
+const KNOWN_ARCH: &[(&str, libc::c_long)] = syscalls![
+    SYS_open, SYS_stat, SYS_lstat, SYS_access, SYS_readlink, SYS_getdents,
+    SYS_dup2, SYS_pipe, SYS_arch_prctl, SYS_time, SYS_fork, SYS_vfork,
+    SYS_getrlimit, SYS_mkdir, SYS_unlink, SYS_rename, SYS_select, SYS_poll,
+];
+#[cfg(not(target_arch = "x86_64"))]
+const KNOWN_ARCH: &[(&str, libc::c_long)] = &[];
+
+/// What perl needs to read its modules and filter text. Notably absent:
+/// anything that creates processes, sockets or files, or runs programs.
+#[rustfmt::skip]
+const DEFAULT_ALLOW: &[&str] = &[
+    "read", "write", "readv", "writev", "pread64", "openat", "close",
+    "close_range", "fstat", "newfstatat", "statx", "lseek", "mmap", "mprotect",
+    "munmap", "mremap", "madvise", "brk", "rt_sigaction", "rt_sigprocmask",
+    "rt_sigreturn", "sigaltstack", "ioctl", "faccessat", "faccessat2",
+    "readlinkat", "getdents64", "getcwd", "fcntl", "dup", "dup3", "getpid",
+    "gettid", "getppid", "getuid", "geteuid", "getgid", "getegid",
+    "getgroups", "uname", "sysinfo", "getrusage", "times", "clock_gettime",
+    "clock_getres", "gettimeofday", "futex", "set_tid_address",
+    "set_robust_list", "rseq", "prlimit64", "getrandom", "sched_getaffinity",
+    "sched_yield", "exit", "exit_group",
+    // x86_64 only, ignored elsewhere.
+    "open", "stat", "lstat", "access", "readlink", "getdents", "dup2",
+    "arch_prctl", "time", "getrlimit",
+];
+
+/// What the pool's zygote needs on top of the allowlist: the sandbox loads
+/// the filter before it execs perl, and the zygote forks runs, waits for
+/// them, relays their output and hands them the allowlist, which is then
+/// stacked on top of this one.
+#[rustfmt::skip]
+const ZYGOTE_ALLOW: &[&str] = &[
+    "execve", "clone", "wait4", "pipe2", "pselect6", "prctl", "seccomp",
+    // x86_64 only, ignored elsewhere.
+    "fork", "pipe", "select",
+];
+
+#[derive(Clone)]
+pub struct SeccompFilter {
+    /// Names of allowed syscalls, as given in the config.
+    names: Vec<String>,
+    program: Vec<libc::sock_filter>,
+}
+
+impl fmt::Debug for SeccompFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SeccompFilter")
+            .field("names", &self.names)
+            .field("len", &self.program.len())
+            .finish()
+    }
+}
+
+const fn stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+const fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+impl SeccompFilter {
+    /// Builds the filter from `SECCOMP_SYSCALLS` (or the default allowlist)
+    /// plus `SECCOMP_EXTRA_SYSCALLS`. Returns `None` when seccomp is disabled.
+    pub fn from_config(cfg: &Config) -> eyre::Result<Option<Self>> {
+        if !cfg.seccomp {
+            return Ok(None);
+        }
+
+        let mut names = match &cfg.seccomp_syscalls {
+            Some(names) => names.clone(),
+            None => DEFAULT_ALLOW.iter().map(|&name| name.to_owned()).collect(),
+        };
+        names.extend(cfg.seccomp_extra_syscalls.iter().cloned());
+        Self::new(names, cfg.seccomp_syscalls.is_some()).map(Some)
+    }
+
+    /// `strict` makes names unknown on this architecture an error rather than
+    /// being skipped.
+    fn new(names: Vec<String>, strict: bool) -> eyre::Result<Self> {
+        use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+        let mut numbers = Vec::new();
+        let mut known_names = Vec::new();
+        for name in names {
+            let nr = KNOWN
+                .iter()
+                .chain(KNOWN_ARCH)
+                .find(|(sys, _)| sys.strip_prefix("SYS_") == Some(&name))
+                .map(|&(_, nr)| nr);
+            match nr {
+                Some(nr) => {
+                    numbers.push(nr as u32);
+                    known_names.push(name);
+                }
+                None if strict => bail!("unknown syscall {name:?} in seccomp allowlist"),
+                None => {}
+            }
+        }
+        numbers.sort_unstable();
+        numbers.dedup();
+
+        let kill = libc::SECCOMP_RET_KILL_PROCESS;
+        let mut program = vec![
+            stmt(BPF_LD | BPF_W | BPF_ABS, ARCH_OFFSET),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+            stmt(BPF_RET | BPF_K, kill),
+            stmt(BPF_LD | BPF_W | BPF_ABS, NR_OFFSET),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        program.extend([
+            jump(BPF_JMP | libc::BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1),
+            stmt(BPF_RET | BPF_K, kill),
+        ]);
+        // Each comparison jumps to the ALLOW at the very end or falls through
+        // to the next one, the last one falls through to KILL.
+        let count = numbers.len();
+        for (idx, nr) in numbers.into_iter().enumerate() {
+            let to_allow = u8::try_from(count - idx).expect("allowlist is too long");
+            program.push(jump(BPF_JMP | BPF_JEQ | BPF_K, nr, to_allow, 0));
+        }
+        program.extend([
+            stmt(BPF_RET | BPF_K, kill),
+            stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW),
+        ]);
+
+        Ok(Self {
+            names: known_names,
+            program,
+        })
+    }
+
+    /// The allowlist extended with what the zygote needs, see
+    /// [`ZYGOTE_ALLOW`].
+    pub fn for_zygote(&self) -> eyre::Result<Self> {
+        let mut names = self.names.clone();
+        for &name in ZYGOTE_ALLOW {
+            if !names.iter().any(|known| known == name) {
+                names.push(name.to_owned());
+            }
+        }
+        Self::new(names, false)
+    }
+
+    /// Serialized program in the format bwrap's `--seccomp` and the
+    /// zygote expect.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(self.program.len() * 8);
+        for insn in &self.program {
+            res.extend_from_slice(&insn.code.to_ne_bytes());
+            res.extend_from_slice(&[insn.jt, insn.jf]);
+            res.extend_from_slice(&insn.k.to_ne_bytes());
+        }
+        res
+    }
+
+    /// Writes the program to a fresh memfd, positioned at the start.
+    pub fn memfd(&self) -> io::Result<OwnedFd> {
+        // SAFETY: the name is a valid C string.
+        let fd = unsafe { libc::memfd_create(c"perlsub-seccomp".as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: `fd` was just created and is owned by nobody else.
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(&self.to_bytes())?;
+        file.rewind()?;
+        Ok(file.into())
+    }
+
+    /// The same allowlist as a kafel policy for nsjail's `--seccomp_string`.
+    pub fn kafel(&self) -> String {
+        format!("ALLOW {{ {} }} DEFAULT KILL", self.names.join(", "))
+    }
+
+    /// Installs the filter on the current process. Meant to be called from a
+    /// `pre_exec` hook right before exec, after everything else, since it
+    /// forbids most of what the hook could want to do.
+    pub fn install(&self) -> io::Result<()> {
+        let program = libc::sock_fprog {
+            len: self.program.len() as libc::c_ushort,
+            filter: self.program.as_ptr().cast_mut(),
+        };
+        // SAFETY: `program` points to a valid filter that outlives the call,
+        // the kernel copies it.
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+                || libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &program) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
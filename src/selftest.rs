@@ -0,0 +1,342 @@
+//! Canary scripts that check the sandbox actually confines perl.
+
+use std::{fmt, fs, path::PathBuf};
+
+use color_eyre::eyre;
+
+use crate::{
+    config::Config,
+    native,
+    perl::{run_perl, Mode, PerlError},
+    pool::Pool,
+    subst,
+};
+
+const INPUT: &str = "foo";
+const SECRET: &str = "perlsub selftest secret";
+
+enum Expect {
+    /// Perl must succeed and print exactly this.
+    Output(&'static str),
+    /// Perl must fail, for any reason other than a syntax error in the
+    /// canary itself.
+    Failure,
+    /// The Safe compartment must refuse to compile the canary.
+    Trapped,
+}
+
+struct Case {
+    name: &'static str,
+    expr: String,
+    expect: Expect,
+    /// Run in the Safe compartment. The other canaries check what the
+    /// sandbox stops on its own.
+    compartment: bool,
+}
+
+/// Outcome of a single case.
+struct CaseResult {
+    name: &'static str,
+    error: Option<String>,
+}
+
+pub struct Report {
+    results: Vec<CaseResult>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|res| res.error.is_none())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for res in &self.results {
+            match &res.error {
+                None => writeln!(f, "ok    {}", res.name)?,
+                Some(err) => writeln!(f, "FAIL  {}: {err}", res.name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Quotes `s` as a perl single-quoted string.
+fn perl_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Scratch directory on the host for the file canaries. It must not be
+/// visible inside the sandbox.
+struct Scratch {
+    dir: PathBuf,
+}
+
+impl Scratch {
+    fn create() -> eyre::Result<Self> {
+        let dir = std::env::temp_dir().join(format!("perlsub-selftest-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("secret"), SECRET)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, name: &str) -> String {
+        self.dir.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn corpus(scratch: &Scratch, cfg: &Config) -> Vec<Case> {
+    let mut cases = vec![
+        Case {
+            name: "trivial substitution succeeds",
+            expr: "s/foo/bar/".to_owned(),
+            expect: Expect::Output("bar\n"),
+            compartment: false,
+        },
+        Case {
+            name: "network is unreachable",
+            expr: "require Socket; \
+                   socket(my $s, Socket::PF_INET(), Socket::SOCK_STREAM(), 0) or die $!; \
+                   connect($s, Socket::pack_sockaddr_in(53, Socket::inet_aton('1.1.1.1'))) or die $!"
+                .to_owned(),
+            expect: Expect::Failure,
+            compartment: false,
+        },
+        Case {
+            name: "files can't be written",
+            expr: format!(
+                "open(my $f, '>', {}) or die $!; print $f 'x' or die $!; close $f or die $!",
+                perl_quote(&scratch.path("written")),
+            ),
+            expect: Expect::Failure,
+            compartment: false,
+        },
+        Case {
+            name: "processes can't be forked",
+            expr: "defined(my $pid = fork) or die $!; exit 0 unless $pid; waitpid $pid, 0".to_owned(),
+            expect: Expect::Failure,
+            compartment: false,
+        },
+        Case {
+            name: "runaway loops are stopped",
+            expr: "1 while 1".to_owned(),
+            expect: Expect::Failure,
+            compartment: false,
+        },
+        Case {
+            name: "files outside allow_dirs can't be read",
+            expr: format!(
+                "open(my $f, '<', {}) or die $!; $_ = <$f> // die $!",
+                perl_quote(&scratch.path("secret")),
+            ),
+            expect: Expect::Failure,
+            compartment: false,
+        },
+    ];
+    if cfg.opcodes.is_some() {
+        cases.push(Case {
+            name: "the compartment traps open",
+            expr: format!(
+                "open(my $f, '<', {}) or die $!",
+                perl_quote(&scratch.path("secret")),
+            ),
+            expect: Expect::Trapped,
+            compartment: true,
+        });
+        cases.push(Case {
+            name: "the compartment allows plain expressions",
+            expr: "$_ = join ',', sort map { uc } reverse @W, sprintf '%.1f', sqrt 4".to_owned(),
+            expect: Expect::Output("2.0,FOO\n"),
+            compartment: true,
+        });
+    }
+    cases
+}
+
+/// Substitution the in-process engine must handle exactly like perl, or
+/// leave to perl.
+struct ParityCase {
+    expr: &'static str,
+    input: &'static str,
+    mode: Mode,
+    /// Whether the in-process engine takes it on.
+    native: bool,
+}
+
+const fn parity(expr: &'static str, input: &'static str) -> ParityCase {
+    ParityCase {
+        expr,
+        input,
+        mode: Mode::Line,
+        native: true,
+    }
+}
+
+const fn parity_full(expr: &'static str, input: &'static str) -> ParityCase {
+    ParityCase {
+        expr,
+        input,
+        mode: Mode::Full,
+        native: true,
+    }
+}
+
+/// Where the `regex` crate would disagree with perl.
+const fn parity_fallback(expr: &'static str, input: &'static str) -> ParityCase {
+    ParityCase {
+        expr,
+        input,
+        mode: Mode::Line,
+        native: false,
+    }
+}
+
+const PARITY_CORPUS: &[ParityCase] = &[
+    parity("s/teh/the/g", "teh cat and teh dog"),
+    parity("s/foo/bar/i", "FOO foo"),
+    parity("s/(\\w+) (\\w+)/$2 $1/", "hello world"),
+    parity("s/(\\d+)/<${1}0>/g", "a1b22c333"),
+    parity("s{o}{0}g", "foo\nboo\n"),
+    parity("s|a/b|[$&]|", "xa/by"),
+    parity("s/\\s+$//", "trailing   "),
+    parity("s/^\\s+//", "   leading"),
+    parity("s/./\\//g", "ab"),
+    parity("s/é/e/g", "café\nnaïve é"),
+    parity("s/\\bcat\\b/dog/g", "cat concat cat"),
+    parity("s/(?i)ab(?-i)c/x/g", "ABc ABC"),
+    parity("s/a.c/x/s", "a\nc"),
+    parity("s/ at /@ /", "me at example.org"),
+    parity("s/(a)|b/[$1]/g", "abc"),
+    parity("s/x*/-/", "abc"),
+    parity("s/\\w+/X/g", "héllo wörld ٣"),
+    parity("s/\\d/#/g", "1٣"),
+    parity("s/k/x/gi", "K\u{212A}k"),
+    parity("s/[^a-c]+/./g", "abxyzc"),
+    parity("s/a{2,}/b/", "caaat"),
+    parity("s/\\s/_/g", "a\u{85}b\u{A0}c\u{B}d"),
+    parity("s/(?<w>o+)/<$1>/", "foo"),
+    parity("s/./$&$&/g", "ab"),
+    parity("s/(.)(.)(.)(.)(.)(.)(.)(.)(.)(.)/$10/", "abcdefghijk"),
+    parity_full("s/^b/B/mg", "a\nb\nbb"),
+    parity_full("s/\\n/ /g", "one\ntwo\nthree"),
+    parity_fallback("s/ss/x/i", "Straße"),
+    parity_fallback("s/(?i)ss/x/", "Straße"),
+    parity_fallback("s/(?i:é)/e/", "É"),
+];
+
+/// Checks the in-process engine against perl on [`PARITY_CORPUS`].
+async fn check_parity(
+    cfg: &Config,
+    pool: &Pool,
+    results: &mut Vec<CaseResult>,
+) -> eyre::Result<()> {
+    let mut error = None;
+    for case in PARITY_CORPUS {
+        let subst = subst::parse(case.expr)?;
+        let native = native::run(&[subst], case.input, cfg, case.mode);
+        if !case.native {
+            if native.is_some() {
+                error = Some(format!("{} was run in-process", case.expr));
+                break;
+            }
+            continue;
+        }
+        let Some(native) = native else {
+            error = Some(format!("{} was not run in-process", case.expr));
+            break;
+        };
+        match run_perl(
+            [case.expr],
+            case.input,
+            cfg,
+            pool,
+            case.mode,
+            cfg.opcodes.as_deref(),
+        )
+        .await?
+        {
+            Ok(perl) if perl.text == native.text => {}
+            Ok(perl) => {
+                error = Some(format!(
+                    "{} on {:?}: perl printed {:?}, in-process {:?}",
+                    case.expr, case.input, perl.text, native.text,
+                ));
+                break;
+            }
+            Err(err) => {
+                error = Some(format!("{} failed in perl: {err}", case.expr));
+                break;
+            }
+        }
+    }
+    results.push(CaseResult {
+        name: "in-process substitutions match perl",
+        error,
+    });
+    Ok(())
+}
+
+/// Runs the canary corpus through the same path messages take.
+pub async fn run(cfg: &Config, pool: &Pool) -> eyre::Result<Report> {
+    let scratch = Scratch::create()?;
+    if cfg
+        .allow_dirs
+        .iter()
+        .any(|dir| scratch.dir.starts_with(dir))
+    {
+        tracing::warn!(dir = ?scratch.dir, "selftest scratch directory is inside allow_dirs");
+    }
+
+    let mut results = Vec::new();
+    for case in corpus(&scratch, cfg) {
+        let opcodes = cfg.opcodes.as_deref().filter(|_| case.compartment);
+        let res = run_perl([case.expr.as_str()], INPUT, cfg, pool, Mode::Line, opcodes).await?;
+        let error = match (case.expect, res) {
+            (Expect::Output(expected), Ok(out)) if out.text == expected => None,
+            (Expect::Output(expected), Ok(out)) => {
+                Some(format!("expected output {expected:?}, got {:?}", out.text))
+            }
+            (Expect::Output(_), Err(err)) => Some(format!("perl failed: {err}")),
+            (Expect::Failure, Ok(out)) => {
+                Some(format!("unexpectedly succeeded with output {:?}", out.text))
+            }
+            (Expect::Failure, Err(err @ PerlError::Syntax(_))) => {
+                Some(format!("canary doesn't compile: {err}"))
+            }
+            (Expect::Failure, Err(err)) => {
+                tracing::debug!(case = case.name, %err, "canary failed as expected");
+                None
+            }
+            (Expect::Trapped, Err(PerlError::Trapped(_))) => None,
+            (Expect::Trapped, Ok(out)) => {
+                Some(format!("unexpectedly succeeded with output {:?}", out.text))
+            }
+            (Expect::Trapped, Err(err)) => Some(format!("failed without a trap: {err}")),
+        };
+        results.push(CaseResult {
+            name: case.name,
+            error,
+        });
+    }
+
+    if cfg.native_subst {
+        check_parity(cfg, pool, &mut results).await?;
+    }
+
+    // Belt and braces: perl could have lied about failing.
+    if fs::metadata(scratch.path("written")).is_ok() {
+        results.push(CaseResult {
+            name: "no file appeared on the host",
+            error: Some("perl managed to create a file".to_owned()),
+        });
+    }
+
+    Ok(Report { results })
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+
+use color_eyre::eyre::{self, bail, eyre};
+use serde::{Deserialize, Serialize};
+use teloxide::{
+    prelude::Requester,
+    types::{ChatId, Message},
+    Bot,
+};
+
+use crate::{delivery::DeliveryMode, policy::Policy};
+
+/// Per-chat knobs, changed with `/set <key> <value>` by chat admins.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatSettings {
+    /// Reply with a short description when perl fails.
+    pub report_errors: bool,
+    /// How results longer than a single message are sent.
+    pub delivery: DeliveryMode,
+    /// What expressions may do in this chat, on top of the bot-wide policy.
+    pub policy: Policy,
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        Self {
+            report_errors: true,
+            delivery: DeliveryMode::default(),
+            policy: Policy::default(),
+        }
+    }
+}
+
+impl ChatSettings {
+    fn set(&mut self, key: &str, value: &str) -> eyre::Result<()> {
+        match key {
+            "errors" => self.report_errors = parse_bool(value)?,
+            "delivery" => {
+                self.delivery = DeliveryMode::parse(value)
+                    .ok_or_else(|| eyre!("expected split/document/both, got {value:?}"))?
+            }
+            "eval" => self.policy.eval = parse_bool(value)?,
+            "codeblocks" => self.policy.code_blocks = parse_bool(value)?,
+            "statements" => self.policy.statements = parse_bool(value)?,
+            "mode" => {
+                self.policy = match value {
+                    "pure" => Policy::PURE,
+                    "perl" => Policy::default(),
+                    _ => bail!("expected pure/perl, got {value:?}"),
+                }
+            }
+            _ => bail!("unknown setting {key:?}"),
+        }
+        Ok(())
+    }
+}
+
+/// The settings as `/set` takes them, one `key = value` per line.
+impl fmt::Display for ChatSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let on_off = |value| if value { "on" } else { "off" };
+        writeln!(f, "errors = {}", on_off(self.report_errors))?;
+        writeln!(f, "delivery = {}", self.delivery.name())?;
+        writeln!(f, "eval = {}", on_off(self.policy.eval))?;
+        writeln!(f, "codeblocks = {}", on_off(self.policy.code_blocks))?;
+        write!(f, "statements = {}", on_off(self.policy.statements))
+    }
+}
+
+fn parse_bool(value: &str) -> eyre::Result<bool> {
+    match value {
+        "on" | "true" | "yes" | "1" => Ok(true),
+        "off" | "false" | "no" | "0" => Ok(false),
+        _ => bail!("expected on/off, got {value:?}"),
+    }
+}
+
+#[derive(Clone)]
+pub struct SettingsStore {
+    tree: sled::Tree,
+}
+
+impl SettingsStore {
+    pub fn open(db: &sled::Db) -> eyre::Result<Self> {
+        Ok(Self {
+            tree: db.open_tree("chat_settings")?,
+        })
+    }
+
+    pub fn get(&self, chat: ChatId) -> eyre::Result<ChatSettings> {
+        match self.tree.get(chat.0.to_le_bytes())? {
+            Some(raw) => Ok(serde_json::from_slice(&raw)?),
+            None => Ok(ChatSettings::default()),
+        }
+    }
+
+    fn put(&self, chat: ChatId, settings: &ChatSettings) -> eyre::Result<()> {
+        self.tree
+            .insert(chat.0.to_le_bytes(), serde_json::to_vec(settings)?)?;
+        Ok(())
+    }
+}
+
+/// Handles a `/set <key> <value>` command. Returns `None` if the message is
+/// not a command for the bot called `username`, otherwise the text to reply
+/// with.
+pub async fn handle_command(
+    bot: &Bot,
+    username: &str,
+    store: &SettingsStore,
+    message: &Message,
+) -> eyre::Result<Option<String>> {
+    let text = match message.text() {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+    let mut words = text.split_whitespace();
+    let command = match words.next() {
+        Some(command) => command,
+        None => return Ok(None),
+    };
+    // Commands in groups may be addressed as `/set@botname`, which other
+    // bots in the chat have to leave alone.
+    let (command, addressee) = match command.split_once('@') {
+        Some((command, addressee)) => (command, Some(addressee)),
+        None => (command, None),
+    };
+    if command != "/set" || addressee.is_some_and(|name| !name.eq_ignore_ascii_case(username)) {
+        return Ok(None);
+    }
+
+    if !message.chat.is_private() {
+        let user = match message.from() {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        let member = bot.get_chat_member(message.chat.id, user.id).await?;
+        if !member.is_privileged() {
+            return Ok(Some("only chat admins can change settings".to_owned()));
+        }
+    }
+
+    let mut settings = store.get(message.chat.id)?;
+    let reply = match (words.next(), words.next()) {
+        (Some(key), Some(value)) => match settings.set(key, value) {
+            Ok(()) => {
+                store.put(message.chat.id, &settings)?;
+                format!("{key} = {value}")
+            }
+            Err(err) => err.to_string(),
+        },
+        _ => settings.to_string(),
+    };
+    Ok(Some(reply))
+}
@@ -0,0 +1,518 @@
+//! Parser for perl's `s///`, `tr///` and `m//` syntax.
+
+use std::fmt;
+
+/// Modifiers perl accepts after `s///`.
+const SUBST_MODIFIERS: &str = "msixnpodualgcer";
+
+/// Modifiers perl accepts after `m//`.
+const MATCH_MODIFIERS: &str = "msixnpodualgc";
+
+/// Modifiers perl accepts after `tr///`.
+const TR_MODIFIERS: &str = "cdsr";
+
+/// Which quote-like operator an expression uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    /// `s///`
+    Substitute,
+    /// `tr///`, or its alias `y///`.
+    Transliterate,
+    /// `m//`, or just `//`.
+    Match,
+}
+
+/// Opening and closing character of a pattern or replacement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delimiter {
+    /// The same character on both ends, like `/`.
+    Single(char),
+    /// A bracket pair, which nests inside the part, like `{}`.
+    Paired(char, char),
+}
+
+impl Delimiter {
+    fn new(open: char) -> Self {
+        match open {
+            '(' => Self::Paired('(', ')'),
+            '[' => Self::Paired('[', ']'),
+            '{' => Self::Paired('{', '}'),
+            '<' => Self::Paired('<', '>'),
+            _ => Self::Single(open),
+        }
+    }
+
+    /// Whether `c` can't appear unescaped in a part with this delimiter.
+    pub fn contains(self, c: char) -> bool {
+        match self {
+            Self::Single(delim) => c == delim,
+            Self::Paired(open, close) => c == open || c == close,
+        }
+    }
+}
+
+/// Modifiers of an expression, the ones perl doesn't care about for our
+/// purposes are only kept in `raw`. Transliteration modifiers only ever end
+/// up in `raw`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Modifiers<'a> {
+    pub raw: &'a str,
+    pub global: bool,
+    pub ignore_case: bool,
+    pub multi_line: bool,
+    pub single_line: bool,
+    /// Number of `e`s, each evaluates the replacement once more.
+    pub eval: usize,
+}
+
+impl<'a> Modifiers<'a> {
+    fn parse(raw: &'a str, operator: Operator) -> Result<Self, ParseError> {
+        let mut res = Self {
+            raw,
+            ..Self::default()
+        };
+        let known = match operator {
+            Operator::Substitute => SUBST_MODIFIERS,
+            Operator::Transliterate => TR_MODIFIERS,
+            Operator::Match => MATCH_MODIFIERS,
+        };
+        if let Some(c) = raw.chars().find(|&c| !known.contains(c)) {
+            return Err(ParseError::UnknownModifier(c));
+        }
+        // `tr///s` squeezes, it has nothing to do with `s///s`.
+        if operator == Operator::Transliterate {
+            return Ok(res);
+        }
+
+        for c in raw.chars() {
+            match c {
+                'g' => res.global = true,
+                'i' => res.ignore_case = true,
+                'm' => res.multi_line = true,
+                's' => res.single_line = true,
+                'e' => res.eval += 1,
+                _ => {}
+            }
+        }
+        Ok(res)
+    }
+
+    /// Whether every modifier is one of `allowed`.
+    pub fn only(&self, allowed: &str) -> bool {
+        self.raw.chars().all(|c| allowed.contains(c))
+    }
+}
+
+/// A single line of a sed-style address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Line<'a> {
+    /// `3`, counted from 1.
+    Number(u64),
+    /// `$`
+    Last,
+    /// `/regex/`, the regex without its slashes.
+    Matching(&'a str),
+}
+
+/// Lines an expression applies to, like sed's `2,5s/a/b/`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Address<'a> {
+    Line(Line<'a>),
+    /// From the first line through the second, both included. Like in sed
+    /// the end is only looked for after the start.
+    Range(Line<'a>, Line<'a>),
+}
+
+/// Splits a line address off the start of `s`.
+fn parse_line(s: &str) -> Option<(Line<'_>, &str)> {
+    if let Some(rest) = s.strip_prefix('$') {
+        return Some((Line::Last, rest));
+    }
+    if let Some(rest) = s.strip_prefix('/') {
+        let (regex, rest) = split_part(rest, Delimiter::Single('/'), Part::Pattern).ok()?;
+        return Some((Line::Matching(regex), rest));
+    }
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let number = s[..digits].parse().ok()?;
+    Some((Line::Number(number), &s[digits..]))
+}
+
+/// Splits an address off the start of `s`.
+fn parse_address(s: &str) -> Option<(Address<'_>, &str)> {
+    let (start, rest) = parse_line(s)?;
+    match rest.strip_prefix(',').and_then(parse_line) {
+        Some((end, rest)) => Some((Address::Range(start, end), rest)),
+        None => Some((Address::Line(start), rest)),
+    }
+}
+
+impl Address<'_> {
+    /// Regexes in the address.
+    pub fn patterns(&self) -> impl Iterator<Item = &str> {
+        let (start, end) = match *self {
+            Self::Line(line) => (line, None),
+            Self::Range(start, end) => (start, Some(end)),
+        };
+        [Some(start), end]
+            .into_iter()
+            .flatten()
+            .filter_map(|line| match line {
+                Line::Matching(regex) => Some(regex),
+                _ => None,
+            })
+    }
+}
+
+/// A parsed `s/pattern/replacement/modifiers` expression, or a `tr///`
+/// one, whose search and replacement lists end up in `pattern` and
+/// `replacement`, or a match, whose replacement is empty. Both parts are
+/// kept as written, escapes included.
+#[derive(Debug, Clone, Copy)]
+pub struct Substitution<'a> {
+    /// The whole expression as written.
+    pub source: &'a str,
+    pub address: Option<Address<'a>>,
+    /// The operator through the modifiers, without the address and the
+    /// tail.
+    pub body: &'a str,
+    pub operator: Operator,
+    pub pattern_delimiter: Delimiter,
+    pub pattern: &'a str,
+    pub replacement_delimiter: Delimiter,
+    pub replacement: &'a str,
+    pub modifiers: Modifiers<'a>,
+    /// Statements after the expression, without the separating `;`.
+    pub tail: &'a str,
+}
+
+/// Which part of the expression a [`ParseError`] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Part {
+    Pattern,
+    Replacement,
+}
+
+impl fmt::Display for Part {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Pattern => "pattern",
+            Self::Replacement => "replacement",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// Doesn't start with `s`, `tr`, `y` or `m` and a delimiter, or `/`.
+    NotSubstitution,
+    /// The closing delimiter of a part is missing.
+    Unterminated(Part),
+    /// A bracketed pattern isn't followed by a replacement.
+    MissingReplacement,
+    UnknownModifier(char),
+    /// Something other than a statement follows the modifiers.
+    Trailing(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotSubstitution => f.write_str("not a substitution, transliteration or match"),
+            Self::Unterminated(part) => write!(f, "unterminated {part}"),
+            Self::MissingReplacement => f.write_str("missing replacement after the pattern"),
+            Self::UnknownModifier(c) => write!(f, "unknown modifier `{c}`"),
+            Self::Trailing(rest) => write!(f, "unexpected `{rest}` after the expression"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl ParseError {
+    /// Whether `line`, which failed with this error, was clearly meant as an
+    /// expression. A lone delimiter, like in "y'all" or "s, right", is more
+    /// likely punctuation than a pattern someone is still typing, and so is
+    /// a bare `//` match followed by text, like in "/r/rust/ is cool".
+    pub fn worth_reporting(&self, line: &str) -> bool {
+        match self {
+            Self::NotSubstitution | Self::Unterminated(Part::Pattern) => false,
+            Self::UnknownModifier(_) | Self::Trailing(_) => !line.starts_with('/'),
+            _ => true,
+        }
+    }
+}
+
+/// Whether perl accepts `c` as a quote-like delimiter.
+fn is_delimiter(c: char) -> bool {
+    c.is_ascii_punctuation() && c != '_'
+}
+
+/// Splits `s` after the part that ends with `delim`, nested brackets and
+/// escapes taken into account. Returns the part without the closing
+/// delimiter, and the rest.
+fn split_part(s: &str, delim: Delimiter, part: Part) -> Result<(&str, &str), ParseError> {
+    let mut depth = 0_usize;
+    let mut chars = s.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match (c, delim) {
+            ('\\', _) => {
+                chars.next();
+            }
+            (c, Delimiter::Single(close)) if c == close => {
+                return Ok((&s[..idx], &s[idx + c.len_utf8()..]));
+            }
+            (c, Delimiter::Paired(open, _)) if c == open => depth += 1,
+            (c, Delimiter::Paired(_, close)) if c == close => {
+                if depth == 0 {
+                    return Ok((&s[..idx], &s[idx + c.len_utf8()..]));
+                }
+                depth -= 1;
+            }
+            _ => {}
+        }
+    }
+    Err(ParseError::Unterminated(part))
+}
+
+/// Parses a single `s///`, `tr///`, `y///`, `m//` or `//` expression, as
+/// perl would, optionally after a sed-style address.
+pub fn parse(expr: &str) -> Result<Substitution<'_>, ParseError> {
+    // Only an address if the rest is an expression, otherwise `/foo/` is a
+    // match and "15/03/2024" is a date. That goes for "12/25/" too: after a
+    // line number the operator has to be spelled out, and a bare match
+    // needs a pattern after any address.
+    if let Some((address, rest)) = parse_address(expr) {
+        let after_number = matches!(
+            address,
+            Address::Line(Line::Number(_)) | Address::Range(_, Line::Number(_))
+        );
+        let bare_match = rest.starts_with('/');
+        let res = parse_expr(rest)
+            .ok()
+            .filter(|res| !bare_match || (!after_number && !res.pattern.is_empty()));
+        if let Some(res) = res {
+            return Ok(Substitution {
+                source: expr,
+                address: Some(address),
+                ..res
+            });
+        }
+    }
+    parse_expr(expr)
+}
+
+fn parse_expr(expr: &str) -> Result<Substitution<'_>, ParseError> {
+    let (operator, rest) = if let Some(rest) = expr.strip_prefix('s') {
+        (Operator::Substitute, rest)
+    } else if let Some(rest) = expr.strip_prefix("tr").or_else(|| expr.strip_prefix('y')) {
+        (Operator::Transliterate, rest)
+    } else if let Some(rest) = expr.strip_prefix('m') {
+        (Operator::Match, rest)
+    } else if expr.starts_with('/') {
+        (Operator::Match, expr)
+    } else {
+        return Err(ParseError::NotSubstitution);
+    };
+    let open = rest
+        .chars()
+        .next()
+        .filter(|&c| is_delimiter(c))
+        .ok_or(ParseError::NotSubstitution)?;
+    let pattern_delimiter = Delimiter::new(open);
+    let (pattern, rest) = split_part(&rest[1..], pattern_delimiter, Part::Pattern)?;
+
+    // With brackets the replacement gets its own delimiters, possibly after
+    // some whitespace: `s{a} {b}` or even `s{a}/b/`.
+    let (replacement_delimiter, rest) = match pattern_delimiter {
+        _ if operator == Operator::Match => (pattern_delimiter, rest),
+        Delimiter::Single(_) => (pattern_delimiter, rest),
+        Delimiter::Paired(..) => {
+            let rest = rest.trim_start();
+            let open = rest
+                .chars()
+                .next()
+                .filter(|&c| is_delimiter(c))
+                .ok_or(ParseError::MissingReplacement)?;
+            (Delimiter::new(open), &rest[1..])
+        }
+    };
+    let (replacement, rest) = match operator {
+        Operator::Match => ("", rest),
+        _ => split_part(rest, replacement_delimiter, Part::Replacement)?,
+    };
+
+    let modifiers_len = rest
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    let modifiers = Modifiers::parse(&rest[..modifiers_len], operator)?;
+    let body = &expr[..expr.len() - rest.len() + modifiers_len];
+
+    let rest = rest[modifiers_len..].trim();
+    let tail = match rest.strip_prefix(';') {
+        Some(tail) => tail.trim(),
+        None if rest.is_empty() => rest,
+        None => return Err(ParseError::Trailing(rest.to_owned())),
+    };
+
+    Ok(Substitution {
+        source: expr,
+        address: None,
+        body,
+        operator,
+        pattern_delimiter,
+        pattern,
+        replacement_delimiter,
+        replacement,
+        modifiers,
+        tail,
+    })
+}
+
+/// Lines of a message, sorted into expressions and everything else.
+#[derive(Debug, Default)]
+pub struct Exprs<'a> {
+    pub accepted: Vec<Substitution<'a>>,
+    /// Lines that start like an expression but aren't a valid one.
+    pub rejected: Vec<(&'a str, ParseError)>,
+}
+
+/// Parses every line of `text`. Lines that don't even start like an
+/// expression, such as directives or chatter, are in neither list.
+pub fn parse_lines(text: &str) -> Exprs<'_> {
+    let mut res = Exprs::default();
+    for line in text.lines() {
+        match parse(line) {
+            Ok(subst) => res.accepted.push(subst),
+            Err(ParseError::NotSubstitution) => {}
+            Err(err) => res.rejected.push((line, err)),
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepted() {
+        let subst = parse("s/a/b/").unwrap();
+        assert_eq!(subst.operator, Operator::Substitute);
+        assert_eq!((subst.pattern, subst.replacement), ("a", "b"));
+        assert_eq!(subst.pattern_delimiter, Delimiter::Single('/'));
+
+        let subst = parse("s{a}{b}g").unwrap();
+        assert_eq!((subst.pattern, subst.replacement), ("a", "b"));
+        assert_eq!(subst.pattern_delimiter, Delimiter::Paired('{', '}'));
+        assert!(subst.modifiers.global);
+
+        let subst = parse("s{a} {b}").unwrap();
+        assert_eq!((subst.pattern, subst.replacement), ("a", "b"));
+
+        let subst = parse("s{a}/b/").unwrap();
+        assert_eq!(subst.replacement_delimiter, Delimiter::Single('/'));
+        assert_eq!(subst.replacement, "b");
+
+        let subst = parse("s(a(b))[c]").unwrap();
+        assert_eq!((subst.pattern, subst.replacement), ("a(b)", "c"));
+
+        let subst = parse(r"s/a\/b/c/").unwrap();
+        assert_eq!(subst.pattern, r"a\/b");
+
+        let subst = parse("s|a|b|i ; print").unwrap();
+        assert!(subst.modifiers.ignore_case);
+        assert_eq!((subst.body, subst.tail), ("s|a|b|i", "print"));
+
+        assert_eq!(parse("s/a/b/e").unwrap().modifiers.eval, 1);
+        assert_eq!(parse("s/a/b/ee").unwrap().modifiers.eval, 2);
+
+        let subst = parse("tr/a-z/A-Z/").unwrap();
+        assert_eq!(subst.operator, Operator::Transliterate);
+        assert_eq!((subst.pattern, subst.replacement), ("a-z", "A-Z"));
+        // `s` squeezes here, it isn't `s///s`.
+        assert!(!parse("y/a/b/s").unwrap().modifiers.single_line);
+
+        let subst = parse("m/foo/i").unwrap();
+        assert_eq!(subst.operator, Operator::Match);
+        assert_eq!((subst.pattern, subst.replacement), ("foo", ""));
+        let subst = parse("/foo/").unwrap();
+        assert_eq!(subst.operator, Operator::Match);
+        assert_eq!((subst.pattern, subst.address), ("foo", None));
+    }
+
+    #[test]
+    fn rejected() {
+        for (expr, err) in [
+            ("hello", ParseError::NotSubstitution),
+            ("sure", ParseError::NotSubstitution),
+            ("s", ParseError::NotSubstitution),
+            ("s, right", ParseError::Unterminated(Part::Pattern)),
+            ("y'all", ParseError::Unterminated(Part::Pattern)),
+            ("s/a/b", ParseError::Unterminated(Part::Replacement)),
+            ("s{a}", ParseError::MissingReplacement),
+            ("s/a/b/z", ParseError::UnknownModifier('z')),
+            ("tr/a/b/g", ParseError::UnknownModifier('g')),
+            ("s/a/b/ foo", ParseError::Trailing("foo".to_owned())),
+            ("15/03/2024", ParseError::NotSubstitution),
+            ("3", ParseError::NotSubstitution),
+            ("12/25/", ParseError::NotSubstitution),
+            ("1,3/a/", ParseError::NotSubstitution),
+        ] {
+            assert_eq!(parse(expr).unwrap_err(), err, "{expr:?}");
+        }
+
+        for (line, reported) in [
+            ("hello", false),
+            ("s, right", false),
+            ("y'all", false),
+            ("/r/rust/ is cool", false),
+            ("/usr/bin/env", false),
+            ("/foo/z", false),
+            ("s/a/b", true),
+            ("s{a}", true),
+            ("s/a/b/z", true),
+            ("s/a/b/ foo", true),
+            ("m/foo/z", true),
+        ] {
+            let err = parse(line).unwrap_err();
+            assert_eq!(err.worth_reporting(line), reported, "{line:?}");
+        }
+    }
+
+    #[test]
+    fn addresses() {
+        for (expr, address) in [
+            ("2s/a/b/", Address::Line(Line::Number(2))),
+            ("$s/a/b/", Address::Line(Line::Last)),
+            ("/foo/s/a/b/", Address::Line(Line::Matching("foo"))),
+            (
+                "1,3y/a/b/",
+                Address::Range(Line::Number(1), Line::Number(3)),
+            ),
+            (
+                "/start/,$/x/",
+                Address::Range(Line::Matching("start"), Line::Last),
+            ),
+        ] {
+            let subst = parse(expr).unwrap();
+            assert_eq!(subst.address, Some(address), "{expr:?}");
+            assert_eq!(subst.source, expr);
+        }
+
+        let subst = parse("2s/a/b/; print").unwrap();
+        assert_eq!((subst.body, subst.tail), ("s/a/b/", "print"));
+        assert_eq!(parse("/start/,$/x/").unwrap().pattern, "x");
+        assert_eq!(parse("12m/25/").unwrap().pattern, "25");
+        assert!(parse("$//").is_err());
+    }
+
+    #[test]
+    fn lines() {
+        let exprs = parse_lines("s/a/b/\nhello\n;full\ns/a/b\n2y/a/b/");
+        assert_eq!(exprs.accepted.len(), 2);
+        assert_eq!(
+            exprs.rejected,
+            [("s/a/b", ParseError::Unterminated(Part::Replacement))]
+        );
+    }
+}
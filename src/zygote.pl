@@ -0,0 +1,166 @@
+# Worker of the perl pool: waits for a job on stdin, forks a child for it and
+# relays the child's output to stdout as frames. The child reads the job
+# itself, so that no job's code or input ever passes through this process
+# and ends up in the memory of the runs forked after it.
+#
+# Job: "<code bytes> <input bytes> <resource>=<limit>,... <opcode>,...\n"
+# <code> <input>, with "-" instead of the opcodes to run without a Safe
+# compartment.
+# Frames: "o" <u32 length> <stdout bytes>, "e" <u32 length> <stderr bytes>,
+# and finally "x" <u32 wait status> <u32 CPU time of the run, milliseconds>.
+#
+# Arguments: SYS_prctl SYS_seccomp SYS_prlimit64 FIONREAD [seccomp program]
+
+# Defined before anything else so that the job can't see our lexicals or
+# pragmas, just like with `perl -e`.
+sub run_job { eval $_[0]; $@ }
+
+use strict;
+use warnings;
+use POSIX ();
+
+# Loaded once here rather than by every run.
+use Safe ();
+use feature ();
+use utf8 ();
+
+# `use` is a `require`, which the compartment forbids, so jobs get their
+# pragmas from this instead. It has to call the imports through references
+# captured here, since method lookups inside the compartment only see its
+# own packages.
+my ($utf8_import, $feature_import) = (\&utf8::import, \&feature::import);
+sub pragmas {
+    $utf8_import->('utf8');
+    $feature_import->('feature', sprintf(':%vd', $^V));
+}
+
+use constant {
+    PR_SET_DUMPABLE => 4,
+    SECCOMP_SET_MODE_FILTER => 1,
+    BUF_SIZE => 65536,
+};
+
+my ($sys_prctl, $sys_seccomp, $sys_prlimit64, $fionread, $filter) = @ARGV;
+$filter = pack 'H*', $filter if defined $filter;
+@ARGV = ();
+
+# Runs must not be able to open our stdin through /proc.
+syscall($sys_prctl + 0, PR_SET_DUMPABLE, 0, 0, 0, 0) == 0
+    or die "PR_SET_DUMPABLE: $!\n";
+
+my $buf = '';
+
+sub read_exact {
+    my ($len) = @_;
+    my $res = '';
+    while (length $res < $len) {
+        sysread(STDIN, $res, $len - length $res, length $res) or POSIX::_exit(1);
+    }
+    $res;
+}
+
+sub child {
+    my ($out_r, $out_w, $err_r, $err_w) = @_;
+    POSIX::dup2(fileno $out_w, 1) // die "dup2: $!\n";
+    POSIX::dup2(fileno $err_w, 2) // die "dup2: $!\n";
+    close $_ for $out_r, $out_w, $err_r, $err_w;
+
+    my $header = '';
+    until ($header =~ /\n\z/) {
+        sysread(STDIN, $header, 1, length $header) or POSIX::_exit(1);
+    }
+    my ($code_len, $input_len, $limits, $ops) = split ' ', $header;
+    my $code = read_exact($code_len);
+    my $input = read_exact($input_len);
+    close STDIN;
+
+    for (split /,/, $limits) {
+        my ($resource, $limit) = split /=/;
+        syscall($sys_prlimit64 + 0, 0, $resource + 0, pack('Q Q', $limit, $limit), 0) == 0
+            or die "prlimit: $!\n";
+    }
+    if (defined $filter) {
+        my $prog = pack 'S x![p] p', length($filter) / 8, $filter;
+        syscall($sys_seccomp + 0, SECCOMP_SET_MODE_FILTER, 0, $prog) == 0
+            or die "seccomp: $!\n";
+    }
+
+    # The input is valid UTF-8, it's a Rust string. `:encoding` would make
+    # method calls the compartment can't resolve.
+    binmode STDOUT, ':utf8';
+    binmode STDERR, ':utf8';
+    $code = "BEGIN { pragmas() }\n$code";
+    my $err;
+    if ($ops eq '-') {
+        open STDIN, '<:utf8', \$input or die "stdin: $!\n";
+        $err = run_job($code);
+    } else {
+        # Reading a reopened STDIN doesn't work through a shared glob, but
+        # a different handle that is also called STDIN does.
+        open Zygote::STDIN, '<:utf8', \$input or die "stdin: $!\n";
+        my $compartment = Safe->new;
+        $compartment->permit_only(split /,/, $ops);
+        $compartment->share_from('main', [qw(*STDOUT *STDERR &pragmas)]);
+        no strict 'refs';
+        *{$compartment->root . '::STDIN'} = *Zygote::STDIN;
+        $compartment->reval($code);
+        $err = $@;
+    }
+    # The job may have set `$\`.
+    $\ = undef;
+    print STDERR $err if $err;
+    # _exit doesn't flush.
+    close STDOUT;
+    close STDERR;
+    POSIX::_exit($err ? 255 : 0);
+}
+
+sub relay {
+    my %open = (o => $_[0], e => $_[1]);
+    while (%open) {
+        my $rin = '';
+        vec($rin, fileno $_, 1) = 1 for values %open;
+        next if select(my $rout = $rin, undef, undef, undef) < 0;
+
+        for my $tag (sort keys %open) {
+            my $fh = $open{$tag};
+            next unless vec($rout, fileno $fh, 1);
+            my $len = sysread $fh, $buf, BUF_SIZE;
+            unless ($len) {
+                close $fh;
+                delete $open{$tag};
+                next;
+            }
+            syswrite STDOUT, pack('a N', $tag, $len);
+            syswrite STDOUT, $buf;
+            # Scrubbed in place, so that later runs can't find it in their
+            # copy of our memory.
+            $buf =~ tr/\0/\0/c;
+        }
+    }
+}
+
+while (1) {
+    my $rin = '';
+    vec($rin, 0, 1) = 1;
+    next if select($rin, undef, undef, undef) < 0;
+    my $pending = pack 'L', 0;
+    ioctl(STDIN, $fionread + 0, $pending) or die "FIONREAD: $!\n";
+    # Readable with nothing to read: the pool is gone.
+    last unless unpack 'L', $pending;
+
+    pipe(my $out_r, my $out_w) or die "pipe: $!\n";
+    pipe(my $err_r, my $err_w) or die "pipe: $!\n";
+    my (undef, undef, $user, $system) = times;
+    my $pid = fork // die "fork: $!\n";
+    child($out_r, $out_w, $err_r, $err_w) unless $pid;
+
+    close $out_w;
+    close $err_w;
+    relay($out_r, $err_r);
+    waitpid $pid, 0;
+    my $status = $?;
+    my (undef, undef, $user_after, $system_after) = times;
+    my $cpu = ($user_after - $user + $system_after - $system) * 1000;
+    syswrite STDOUT, pack('a N N', 'x', $status, $cpu + 0.5);
+}